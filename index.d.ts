@@ -4,6 +4,7 @@
 /* auto-generated by NAPI-RS */
 
 export interface Song {
+  /** Derived from the path, so it stays the same across scans */
   id: string
   bitrate?: number
   sampleRate?: number
@@ -17,22 +18,157 @@ export interface Song {
   genre?: Array<string>
   lyrics?: string
   track_no?: string
+  disc_no?: string
+  /** From the total tag, or the part after the slash of `track_no` */
+  track_total?: number
+  /** From the total tag, or the part after the slash of `disc_no` */
+  disc_total?: number
+  composer?: string
+  conductor?: string
+  lyricist?: string
+  bpm?: number
+  /** Musical key, such as `Am` or `8A` */
+  initial_key?: string
+  comment?: string
+  grouping?: string
+  /** Set when the file is flagged as part of a compilation */
+  compilation?: boolean
+  copyright?: string
+  /** Record label, or the publisher when there is none */
+  label?: string
+  isrc?: string
+  title_sort?: string
+  artist_sort?: string
+  album_sort?: string
   playbackUrl?: string
   song_coverPath_high?: string
   song_coverPath_low?: string
   type: string
   playlistId?: string
+  /**
+   * Container detected from the file contents, such as `mpeg` or `mp4`.
+   * Files lofty can't read report their extension instead
+   */
+  container?: string
+  /** Last modification time of the file in milliseconds since the epoch */
+  mtime?: number
+  /** blake3 hash of the file's audio, when `ScanOptions.hashContent` is set */
+  hash?: string
+  /**
+   * How the file differs from the database in incremental scans. Modified
+   * and moved songs keep the `_id` of their database row.
+   */
+  change?: 'added' | 'modified' | 'moved'
+  /** Path stored in the database for a moved song */
+  movedFrom?: string
+  /** MusicBrainz, AcoustID and Discogs identifiers found in the tags */
+  externalIds?: SongIds
+  /**
+   * Loudness normalization from ReplayGain tags, Opus R128 gains or ID3v2
+   * RVA2 frames, in that order of preference
+   */
+  replayGain?: ReplayGain
+}
+export interface ReplayGain {
+  /** In dB, relative to the ReplayGain reference of -18 LUFS */
+  trackGain?: number
+  /** Linear, 1.0 being full scale */
+  trackPeak?: number
+  albumGain?: number
+  albumPeak?: number
+  /**
+   * Where the values were read from. R128 gains are moved to the ReplayGain
+   * reference level and carry no peaks
+   */
+  source: 'replaygain' | 'r128' | 'rva2'
 }
 export interface Album {
+  /** Derived from the album artist and album name */
   album_id: string
   album_name: string
   album_coverPath_high?: string
   album_coverPath_low?: string
   album_artist?: string
+  externalIds?: AlbumIds
 }
 export interface Artists {
+  /** Derived from the artist name */
   artist_id: string
   artist_name: string
+  /**
+   * How the artist is credited on the song. Only set when
+   * `ScanOptions.artistCredits` is used
+   */
+  role?: 'primary' | 'featured' | 'remixer'
+  /** Only set when the tags hold exactly one id per artist of the song */
+  externalIds?: ArtistIds
+}
+export interface SongIds {
+  musicbrainzRecordingId?: string
+  /** MusicBrainz id of the track on its release */
+  musicbrainzTrackId?: string
+  musicbrainzWorkId?: string
+  /** Every artist id in the tags, in tag order */
+  musicbrainzArtistIds: Array<string>
+  acoustidId?: string
+}
+export interface AlbumIds {
+  musicbrainzReleaseId?: string
+  musicbrainzReleaseGroupId?: string
+  musicbrainzAlbumArtistIds: Array<string>
+  discogsReleaseId?: string
+  discogsMasterId?: string
+}
+export interface ArtistIds {
+  musicbrainzArtistId?: string
+  discogsArtistId?: string
+}
+/** A song's place on an aggregated album */
+export interface AlbumTrack {
+  songId: string
+  title?: string
+  trackNo?: number
+  discNo?: number
+  duration?: number
+}
+/** An album put together from every song of the scan which belongs to it */
+export interface ScannedAlbum {
+  id: string
+  name: string
+  /** The spelling most tracks use */
+  albumArtist?: string
+  /** The cover most tracks use */
+  coverPathHigh?: string
+  coverPathLow?: string
+  yearFrom?: number
+  yearTo?: number
+  discCount: number
+  /** Sorted by disc and track number */
+  tracks: Array<AlbumTrack>
+}
+export interface ScannedArtist {
+  id: string
+  name: string
+  songCount: number
+  albumCount: number
+}
+/** A database row whose file no longer exists */
+export interface RemovedSong {
+  id: string
+  path: string
+}
+/** A database row whose file turned up under a new path */
+export interface MovedSong {
+  id: string
+  from: string
+  to: string
+}
+/** A file or folder left out of the scan on purpose */
+export interface SkippedPath {
+  path: string
+  reason: 'nomedia' | 'ignoreFile' | 'exclude'
+  /** The `.nomedia` or `.moosyncignore` file responsible, if any */
+  source?: string
 }
 export interface Playlist {
   id: string
@@ -44,4 +180,236 @@ export interface SongWithLen {
   size: number
   current: number
 }
-export function scanFiles(dir: string, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, callback_song: (err: null | Error, result: SongWithLen) => void, callback_playlist: (err: null | Error, result: Playlist) => void, callback_end: (err: null | Error) => void): void
+export interface ScanSummary {
+  status: 'completed' | 'cancelled'
+  total: number
+  songs: number
+  playlists: number
+  songErrors: number
+  playlistErrors: number
+  /** Songs whose database row is out of date */
+  modified: number
+  /** Database rows whose file no longer exists */
+  removed: number
+  /** Database rows whose file was found under a new path */
+  moved: number
+  /** Files left out because an interrupted scan already delivered them */
+  resumed: number
+  /** Paths which could not be read during discovery */
+  warnings: Array<ScanWarning>
+  skipped: Array<SkippedPath>
+}
+export interface ScanWarning {
+  path: string
+  message: string
+}
+export interface ScanProgress {
+  current: number
+  total: number
+}
+/**
+ * Rows written to the database in one transaction, when the scanner writes
+ * songs itself
+ */
+export interface WrittenBatch {
+  songs: Array<string>
+  playlists: Array<string>
+  progress: ScanProgress
+}
+export interface ScanEvent {
+  type: 'song' | 'songs' | 'playlist' | 'written' | 'albums' | 'artists' | 'moved' | 'removed' | 'warning' | 'skipped' | 'error' | 'progress' | 'end'
+  song?: Song
+  songs?: Array<SongWithLen>
+  playlist?: Playlist
+  written?: WrittenBatch
+  albums?: Array<ScannedAlbum>
+  artists?: Array<ScannedArtist>
+  moved?: MovedSong
+  removed?: Array<RemovedSong>
+  warning?: ScanWarning
+  skipped?: SkippedPath
+  phase?: 'songs' | 'playlists'
+  message?: string
+  progress?: ScanProgress
+  summary?: ScanSummary
+}
+export interface ScanOptions {
+  /** Receives songs in arrays instead of one `callback_song` call per song */
+  onSongBatch?: (err: null | Error, result: Array<SongWithLen>) => void
+  /** Maximum number of songs per batch. Defaults to 100 */
+  batchSize?: number
+  /** Flushes a partial batch once its oldest song has waited this long */
+  batchWindowMs?: number
+  /**
+   * Globs for files and folders to leave out, such as `*.bak`. `**` matches
+   * across folders. Patterns without a `/` are matched against the name only
+   */
+  exclude?: Array<string>
+  /** Follow symlinks (and junctions on Windows). Defaults to true */
+  followSymlinks?: boolean
+  /** Don't cross into other filesystems or drives below a root */
+  sameFilesystem?: boolean
+  /** How many folder levels below each root are walked */
+  maxDepth?: number
+  /** Probe files with a missing or unknown extension for audio content */
+  sniffContent?: boolean
+  /**
+   * Receives database rows whose file no longer exists below the scanned
+   * folders, once the scan is done. Only called in incremental scans
+   */
+  onRemoved?: (err: null | Error, result: Array<RemovedSong>) => void
+  /**
+   * Receives database rows whose file turned up under a new path. The song
+   * itself is still delivered, with `change` set to `moved` and its original
+   * `_id`. Moves are matched by audio hash, or by title, duration, album and
+   * artists for rows without a stored hash
+   */
+  onMoved?: (err: null | Error, result: MovedSong) => void
+  /**
+   * Also compare audio hashes to find songs changed without a change in size
+   * or modification time. Reads every file in full
+   */
+  hashContent?: boolean
+  /**
+   * Where incremental scans look up known songs. Defaults to Moosync's own
+   * `allsongs` table
+   */
+  databaseSchema?: DatabaseSchema
+  /**
+   * Keep extracted songs in a cache next to the thumbnails and serve files
+   * which haven't changed from it. Forced scans refresh the cache without
+   * reading it. Pass an empty `databaseDir` to rely on the cache alone
+   */
+  cache?: boolean
+  /**
+   * Keep a journal of delivered files next to the thumbnails, so that a scan
+   * which crashed or was aborted picks up where it stopped the next time the
+   * same folders are scanned. Only files JS has actually received count as
+   * delivered. Playlists are always read again
+   */
+  resume?: boolean
+  /**
+   * Write songs, albums, artists, genres and playlists straight into the
+   * database at `databaseDir`. Songs and playlists are then not handed to JS,
+   * `onWritten` gets the ids of each committed batch instead
+   */
+  writeDatabase?: boolean
+  onWritten?: (err: null | Error, result: WrittenBatch) => void
+  /**
+   * Group the songs of this scan into albums and artists, delivered once
+   * song scanning is done. Implied by `onAlbums` and `onArtists`. Songs an
+   * incremental scan leaves alone, or a resumed one already delivered, are
+   * not part of them, so such scans raise a warning. Use `force` for
+   * complete albums
+   */
+  aggregate?: boolean
+  onAlbums?: (err: null | Error, result: Array<ScannedAlbum>) => void
+  onArtists?: (err: null | Error, result: Array<ScannedArtist>) => void
+  /**
+   * Read featured artists, collaborations and remixers out of artist tags and
+   * titles instead of only splitting on `artistSplit`
+   */
+  artistCredits?: ArtistCredits
+}
+/** How `ScanOptions.artistCredits` splits credits such as `A ft. B, C x D` */
+export interface ArtistCredits {
+  /**
+   * Names kept whole even though they contain a separator, such as
+   * `Simon & Garfunkel`. Matched ignoring case
+   */
+  protected?: Array<string>
+  /**
+   * Words introducing featured artists. Defaults to `featuring`, `feat.`,
+   * `feat`, `ft.`, `ft` and `with`
+   */
+  featuredTokens?: Array<string>
+  /**
+   * Separators between artists of the same role. Defaults to `&`, `,`, `x`,
+   * `vs.` and `vs`. `artistSplit` always separates artists too
+   */
+  jointTokens?: Array<string>
+  /**
+   * Also read `(feat. X)` and `(X Remix)` credits from titles. Defaults to
+   * true
+   */
+  fromTitle?: boolean
+}
+/**
+ * Table and columns holding known songs. Unset names fall back to the
+ * `allsongs` layout
+ */
+export interface DatabaseSchema {
+  /** Defaults to `allsongs` */
+  table?: string
+  /** Defaults to `_id` */
+  idColumn?: string
+  /** Defaults to `path` */
+  pathColumn?: string
+  /** Defaults to `size` */
+  sizeColumn?: string
+  /** Modification time in milliseconds. Defaults to `mtime` if present */
+  mtimeColumn?: string
+  /** blake3 hash of the file's audio. Defaults to `hash` if present */
+  hashColumn?: string
+  /** Used to match moved files without a hash. Defaults to `title` if present */
+  titleColumn?: string
+  /** Defaults to `duration` if present */
+  durationColumn?: string
+  /**
+   * SQL expression limiting which rows count as known songs, such as
+   * `type = 'LOCAL'`
+   */
+  filter?: string
+}
+export interface ScanIteratorResult {
+  value?: ScanEvent
+  done: boolean
+}
+/**
+ * Adds extensions to one of the sets used to recognise files. An extension
+ * already registered under another kind is moved over.
+ */
+export function registerExtensions(kind: 'audio' | 'playlist' | 'lyrics' | 'artwork', extensions: Array<string>): void
+/** Removes extensions from whichever set they were registered in */
+export function unregisterExtensions(extensions: Array<string>): void
+export function getExtensions(kind: 'audio' | 'playlist' | 'lyrics' | 'artwork'): Array<string>
+export function scanFiles(dir: string | Array<string>, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, callback_song: (err: null | Error, result: SongWithLen) => void, callback_playlist: (err: null | Error, result: Playlist) => void, callback_end: (err: null | Error, result: ScanSummary) => void, options?: ScanOptions): ScanHandle
+/**
+ * Same as `scanFiles`, but resolves with the scan summary instead of calling
+ * `callback_end`. Rejects if the scan could not be completed.
+ */
+export function scanFilesAsync(dir: string | Array<string>, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, callback_song: (err: null | Error, result: SongWithLen) => void, callback_playlist: (err: null | Error, result: Playlist) => void, options?: ScanOptions): Promise<ScanSummary>
+/**
+ * Starts a scan and returns an async iterable of typed scan events. Breaking
+ * out of a `for await` loop aborts the scan.
+ */
+export function scanFilesStream(dir: string | Array<string>, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, options?: ScanOptions): ScanEventStream
+/** Handle returned by `scanFiles` which can be used to pause, resume or stop the scan. */
+export class ScanHandle {
+  /**
+   * Stops directory walking, skips queued songs and playlists, and fires
+   * `callback_end` with a `cancelled` status once the scan has wound down.
+   */
+  abort(): void
+  /**
+   * Parks directory walking, pool jobs and playlist parsing at their next
+   * safe point. Songs already handed to JS are not sent again.
+   */
+  pause(): void
+  resume(): void
+  get cancelled(): boolean
+  get paused(): boolean
+}
+/** Async iterable returned by `scanFilesStream`. */
+export class ScanEventStream {
+  [Symbol.asyncIterator](): ScanEventStream
+  next(): Promise<ScanIteratorResult>
+  /**
+   * Called by `for await` when the loop exits early. Aborts the scan and
+   * drops any events which were not consumed yet.
+   */
+  return(): Promise<ScanIteratorResult>
+  abort(): void
+  pause(): void
+  resume(): void
+}
@@ -252,6 +252,13 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { scanFiles } = nativeBinding
+const { registerExtensions, unregisterExtensions, getExtensions, scanFiles, scanFilesAsync, scanFilesStream, ScanHandle, ScanEventStream } = nativeBinding
 
+module.exports.registerExtensions = registerExtensions
+module.exports.unregisterExtensions = unregisterExtensions
+module.exports.getExtensions = getExtensions
 module.exports.scanFiles = scanFiles
+module.exports.scanFilesAsync = scanFilesAsync
+module.exports.scanFilesStream = scanFilesStream
+module.exports.ScanHandle = ScanHandle
+module.exports.ScanEventStream = ScanEventStream
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+  structs::{AlbumTrack, ScannedAlbum, ScannedArtist, Song},
+  utils::leading_number,
+};
+
+/// Counts how often each value was seen and remembers the order they came in,
+/// so ties go to whichever showed up first
+#[derive(Default)]
+struct Votes<T: PartialEq + Clone>(Vec<(T, usize)>);
+
+impl<T: PartialEq + Clone> Votes<T> {
+  fn add(&mut self, value: T) {
+    match self.0.iter_mut().find(|(seen, _)| *seen == value) {
+      Some((_, count)) => *count += 1,
+      None => self.0.push((value, 1)),
+    }
+  }
+
+  fn winner(&self) -> Option<T> {
+    let mut best: Option<&(T, usize)> = None;
+    for entry in &self.0 {
+      if best.is_none_or(|best| entry.1 > best.1) {
+        best = Some(entry);
+      }
+    }
+    best.map(|(value, _)| value.clone())
+  }
+}
+
+#[derive(Default)]
+struct AlbumEntry {
+  names: Votes<String>,
+  album_artists: Votes<String>,
+  covers: Votes<(String, Option<String>)>,
+  years: Vec<u32>,
+  discs: HashSet<u32>,
+  disc_total: u32,
+  tracks: Vec<AlbumTrack>,
+  song_ids: HashSet<String>,
+}
+
+#[derive(Default)]
+struct ArtistEntry {
+  names: Votes<String>,
+  songs: HashSet<String>,
+  albums: HashSet<String>,
+}
+
+/// Groups delivered songs into albums and artists over a whole scan
+#[derive(Default)]
+pub struct Aggregator {
+  albums: HashMap<String, AlbumEntry>,
+  album_order: Vec<String>,
+  artists: HashMap<String, ArtistEntry>,
+  artist_order: Vec<String>,
+}
+
+impl Aggregator {
+  pub fn add(&mut self, song: &Song) {
+    let album_id = song.album.as_ref().map(|album| album.album_id.clone());
+
+    if let Some(album) = &song.album {
+      let entry = self
+        .albums
+        .entry(album.album_id.clone())
+        .or_insert_with(|| {
+          self.album_order.push(album.album_id.clone());
+          AlbumEntry::default()
+        });
+
+      // The same song can turn up again through a playlist
+      if entry.song_ids.insert(song._id.clone()) {
+        entry.names.add(album.album_name.clone());
+        if let Some(album_artist) = &album.album_artist {
+          entry.album_artists.add(album_artist.clone());
+        }
+        if let Some(high) = album
+          .album_cover_path_high
+          .as_ref()
+          .or(song.high_path.as_ref())
+        {
+          let low = album
+            .album_cover_path_low
+            .as_ref()
+            .or(song.low_path.as_ref());
+          entry.covers.add((high.clone(), low.cloned()));
+        }
+        if let Some(year) = song.year.as_deref().and_then(leading_number) {
+          entry.years.push(year);
+        }
+
+        let disc_no = song.disc_no.as_deref().and_then(leading_number);
+        if let Some(disc_no) = disc_no {
+          entry.discs.insert(disc_no);
+        }
+        if let Some(total) = song.disc_total {
+          entry.disc_total = entry.disc_total.max(total);
+        }
+
+        entry.tracks.push(AlbumTrack {
+          song_id: song._id.clone(),
+          title: song.title.clone(),
+          track_no: song.track_no.as_deref().and_then(leading_number),
+          disc_no,
+          duration: song.duration,
+        });
+      }
+    }
+
+    for artist in &song.artists {
+      let entry = self
+        .artists
+        .entry(artist.artist_id.clone())
+        .or_insert_with(|| {
+          self.artist_order.push(artist.artist_id.clone());
+          ArtistEntry::default()
+        });
+
+      if entry.songs.insert(song._id.clone()) {
+        entry.names.add(artist.artist_name.clone());
+      }
+      if let Some(album_id) = &album_id {
+        entry.albums.insert(album_id.clone());
+      }
+    }
+  }
+
+  pub fn albums(&mut self) -> Vec<ScannedAlbum> {
+    let mut albums = vec![];
+    for id in &self.album_order {
+      let Some(mut entry) = self.albums.remove(id) else {
+        continue;
+      };
+
+      entry.tracks.sort_by_key(|track| {
+        (
+          track.disc_no.unwrap_or(1),
+          track.track_no.unwrap_or(u32::MAX),
+        )
+      });
+      let cover = entry.covers.winner();
+
+      albums.push(ScannedAlbum {
+        _id: id.clone(),
+        name: entry.names.winner().unwrap_or_default(),
+        album_artist: entry.album_artists.winner(),
+        cover_path_high: cover.as_ref().map(|(high, _)| high.clone()),
+        cover_path_low: cover.and_then(|(_, low)| low),
+        year_from: entry.years.iter().min().copied(),
+        year_to: entry.years.iter().max().copied(),
+        disc_count: (entry.discs.len() as u32).max(entry.disc_total).max(1),
+        tracks: entry.tracks,
+      });
+    }
+
+    albums
+  }
+
+  pub fn artists(&mut self) -> Vec<ScannedArtist> {
+    let mut artists = vec![];
+    for id in &self.artist_order {
+      let Some(entry) = self.artists.remove(id) else {
+        continue;
+      };
+
+      artists.push(ScannedArtist {
+        _id: id.clone(),
+        name: entry.names.winner().unwrap_or_default(),
+        song_count: entry.songs.len() as u32,
+        album_count: entry.albums.len() as u32,
+      });
+    }
+
+    artists
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::structs::{Album, Artists};
+
+  fn artist(id: &str, name: &str) -> Artists {
+    Artists {
+      artist_id: id.to_string(),
+      artist_name: name.to_string(),
+      ..Default::default()
+    }
+  }
+
+  fn track(
+    id: &str,
+    album_artist: &str,
+    year: &str,
+    track_no: &str,
+    artists: Vec<Artists>,
+  ) -> Song {
+    Song {
+      _id: id.to_string(),
+      album: Some(Album {
+        album_id: "album".to_string(),
+        album_name: "Album".to_string(),
+        album_artist: Some(album_artist.to_string()),
+        ..Default::default()
+      }),
+      year: Some(year.to_string()),
+      track_no: Some(track_no.to_string()),
+      artists,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn counts_votes_with_ties_going_to_the_first() {
+    let mut votes = Votes::default();
+    assert_eq!(votes.winner(), None);
+    for value in ["b", "a", "a", "b", "c"] {
+      votes.add(value);
+    }
+    assert_eq!(votes.winner(), Some("b"));
+    votes.add("a");
+    assert_eq!(votes.winner(), Some("a"));
+  }
+
+  #[test]
+  fn merges_album_tags_across_tracks() {
+    let mut aggregator = Aggregator::default();
+    aggregator.add(&track("1", "The Band", "2019-05-01", "2/10", vec![]));
+    aggregator.add(&track("2", "the band", "2021", "1", vec![]));
+    aggregator.add(&track("3", "The Band", "2020", "3", vec![]));
+    // The same song again, from a playlist
+    aggregator.add(&track("3", "the band", "2020", "3", vec![]));
+
+    let albums = aggregator.albums();
+    assert_eq!(albums.len(), 1);
+    let album = &albums[0];
+    assert_eq!(album.album_artist.as_deref(), Some("The Band"));
+    assert_eq!((album.year_from, album.year_to), (Some(2019), Some(2021)));
+    assert_eq!(album.disc_count, 1);
+    assert_eq!(
+      album
+        .tracks
+        .iter()
+        .map(|track| track.song_id.as_str())
+        .collect::<Vec<_>>(),
+      ["2", "1", "3"]
+    );
+  }
+
+  #[test]
+  fn counts_songs_and_albums_per_artist() {
+    let mut aggregator = Aggregator::default();
+    aggregator.add(&track(
+      "1",
+      "A",
+      "2020",
+      "1",
+      vec![artist("a", "A"), artist("b", "B")],
+    ));
+    aggregator.add(&track("2", "A", "2020", "2", vec![artist("a", "a")]));
+    aggregator.add(&Song {
+      _id: "3".to_string(),
+      artists: vec![artist("a", "a")],
+      ..Default::default()
+    });
+    aggregator.add(&Song {
+      _id: "4".to_string(),
+      artists: vec![artist("a", "a")],
+      ..Default::default()
+    });
+
+    let artists: Vec<(String, String, u32, u32)> = aggregator
+      .artists()
+      .into_iter()
+      .map(|artist| {
+        (
+          artist._id,
+          artist.name,
+          artist.song_count,
+          artist.album_count,
+        )
+      })
+      .collect();
+    assert_eq!(
+      artists,
+      [
+        ("a".to_string(), "a".to_string(), 4, 1),
+        ("b".to_string(), "B".to_string(), 1, 1),
+      ]
+    );
+  }
+}
@@ -0,0 +1,293 @@
+use std::{
+  mem,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use sqlite3::{Connection, State};
+
+use crate::{
+  error::ScanError,
+  structs::{ScanWarning, Song},
+  utils::{check_directory, open_scratch_database},
+};
+
+const CACHE_FILE: &str = "scan_cache.db";
+
+/// Bump whenever `Song` or the way tags are read into it changes. Caches
+/// written with another version are dropped when opened.
+const CACHE_VERSION: i64 = 11;
+
+/// Songs extracted in earlier scans, kept in a sqlite file next to the
+/// thumbnails. Entries are keyed by path and only served while the file's
+/// size, mtime and (when known) hash still match.
+#[derive(Clone)]
+pub struct ScanCache {
+  connection: Arc<Mutex<Connection>>,
+  /// Set for forced scans. Entries are rewritten but never read
+  refresh: bool,
+  /// Failures to report, collected from the pool
+  warnings: Arc<Mutex<Vec<ScanWarning>>>,
+}
+
+fn create_tables(connection: &Connection) -> Result<(), ScanError> {
+  let mut cursor = connection.prepare("PRAGMA user_version")?;
+  let version = match cursor.next()? {
+    State::Row => cursor.read::<i64>(0)?,
+    State::Done => 0,
+  };
+  drop(cursor);
+
+  if version != CACHE_VERSION {
+    connection.execute(format!(
+      "DROP TABLE IF EXISTS songs;
+      CREATE TABLE songs (
+        path TEXT PRIMARY KEY,
+        size INTEGER NOT NULL,
+        mtime INTEGER,
+        hash TEXT,
+        credits TEXT NOT NULL,
+        song TEXT NOT NULL
+      );
+      PRAGMA user_version = {};",
+      CACHE_VERSION
+    ))?;
+  }
+
+  Ok(())
+}
+
+/// Thumbnails and covers referenced by a cached song have to still exist
+fn covers_exist(song: &Song) -> bool {
+  let album = song.album.as_ref();
+  [
+    song.high_path.as_ref(),
+    song.low_path.as_ref(),
+    album.and_then(|album| album.album_cover_path_high.as_ref()),
+    album.and_then(|album| album.album_cover_path_low.as_ref()),
+  ]
+  .into_iter()
+  .flatten()
+  .all(|path| Path::new(path).exists())
+}
+
+impl ScanCache {
+  /// Opens the cache in `dir`. A cache which can't be read is deleted and
+  /// started over, as it only holds data that can be scanned again.
+  pub fn open(dir: &Path, refresh: bool) -> Result<Self, ScanError> {
+    check_directory(dir.to_path_buf())?;
+
+    let (connection, discarded) = open_scratch_database(&dir.join(CACHE_FILE), create_tables)?;
+
+    Ok(Self {
+      connection: Arc::new(Mutex::new(connection)),
+      refresh,
+      warnings: Arc::new(Mutex::new(discarded.into_iter().collect())),
+    })
+  }
+
+  /// Failures since the last call, which the scan reports as warnings
+  pub fn take_warnings(&self) -> Vec<ScanWarning> {
+    mem::take(&mut *self.warnings.lock().unwrap())
+  }
+
+  /// Returns the song stored for `path` if the file hasn't changed since.
+  /// Entries without a hash don't match while `hash` is set.
+  pub fn get(
+    &self,
+    path: &Path,
+    size: u64,
+    mtime: Option<u64>,
+    hash: Option<&str>,
+    credits: &str,
+  ) -> Option<Song> {
+    if self.refresh {
+      return None;
+    }
+
+    let connection = self.connection.lock().unwrap();
+    let mut cursor = connection
+      .prepare("SELECT size, mtime, hash, credits, song FROM songs WHERE path = ?")
+      .ok()?;
+    cursor.bind(1, path.to_string_lossy().as_ref()).ok()?;
+    if cursor.next().ok()? != State::Row {
+      return None;
+    }
+
+    let unchanged = cursor.read::<i64>(0).ok()? as u64 == size
+      && cursor.read::<i64>(1).ok().map(|mtime| mtime as u64) == mtime
+      && hash.is_none_or(|hash| cursor.read::<String>(2).ok().as_deref() == Some(hash))
+      && cursor.read::<String>(3).ok()? == credits;
+    if !unchanged {
+      return None;
+    }
+
+    let song: Song = serde_json::from_str(&cursor.read::<String>(4).ok()?).ok()?;
+    covers_exist(&song).then_some(song)
+  }
+
+  pub fn put(&self, path: &Path, size: u64, mtime: Option<u64>, song: &Song, credits: &str) {
+    let Ok(encoded) = serde_json::to_string(song) else {
+      return;
+    };
+
+    let connection = self.connection.lock().unwrap();
+    let res = connection
+      .prepare(
+        "INSERT OR REPLACE INTO songs (path, size, mtime, hash, credits, song)
+        VALUES (?, ?, ?, ?, ?, ?)",
+      )
+      .and_then(|mut insert| {
+        insert.bind(1, path.to_string_lossy().as_ref())?;
+        insert.bind(2, size as i64)?;
+        match mtime {
+          Some(mtime) => insert.bind(3, mtime as i64)?,
+          None => insert.bind(3, ())?,
+        }
+        match song.hash.as_deref() {
+          Some(hash) => insert.bind(4, hash)?,
+          None => insert.bind(4, ())?,
+        }
+        insert.bind(5, credits)?;
+        insert.bind(6, encoded.as_str())?;
+        insert.next().map(|_| ())
+      });
+
+    if let Err(e) = res {
+      self.warnings.lock().unwrap().push(ScanWarning {
+        path: path.to_string_lossy().to_string(),
+        message: format!("Failed to cache: {}", e),
+      });
+    }
+  }
+
+  /// Drops entries below `roots` whose file no longer exists
+  pub fn prune(&self, roots: &[PathBuf]) -> Result<(), ScanError> {
+    let roots: Vec<PathBuf> = roots
+      .iter()
+      .filter_map(|root| dunce::canonicalize(root).ok())
+      .collect();
+
+    let connection = self.connection.lock().unwrap();
+    let mut gone = vec![];
+    let mut cursor = connection.prepare("SELECT path FROM songs")?;
+    while let State::Row = cursor.next()? {
+      let path = PathBuf::from(cursor.read::<String>(0)?);
+      if roots.iter().any(|root| path.starts_with(root)) && !path.exists() {
+        gone.push(path);
+      }
+    }
+    drop(cursor);
+
+    let mut delete = connection.prepare("DELETE FROM songs WHERE path = ?")?;
+    for path in gone {
+      delete.reset()?;
+      delete.bind(1, path.to_string_lossy().as_ref())?;
+      delete.next()?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs};
+
+  use super::*;
+
+  fn cache_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(name);
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn song(title: &str) -> Song {
+    Song {
+      title: Some(title.to_string()),
+      hash: Some("hash".to_string()),
+      ..Default::default()
+    }
+  }
+
+  fn title(song: Option<Song>) -> Option<String> {
+    song.and_then(|song| song.title)
+  }
+
+  #[test]
+  fn serves_entries_while_the_file_is_unchanged() {
+    let dir = cache_dir("moosync-cache-unchanged");
+    let path = Path::new("/music/a.flac");
+    let cache = ScanCache::open(&dir, false).unwrap();
+    cache.put(path, 10, Some(1000), &song("Song"), "credits");
+
+    assert_eq!(
+      title(cache.get(path, 10, Some(1000), None, "credits")).as_deref(),
+      Some("Song")
+    );
+    assert!(cache
+      .get(path, 10, Some(1000), Some("hash"), "credits")
+      .is_some());
+    assert!(cache.get(path, 11, Some(1000), None, "credits").is_none());
+    assert!(cache.get(path, 10, Some(2000), None, "credits").is_none());
+    assert!(cache.get(path, 10, None, None, "credits").is_none());
+    assert!(cache
+      .get(path, 10, Some(1000), Some("other"), "credits")
+      .is_none());
+    assert!(cache.get(path, 10, Some(1000), None, "other").is_none());
+
+    // Forced scans rewrite entries without reading them
+    let cache = ScanCache::open(&dir, true).unwrap();
+    assert!(cache.get(path, 10, Some(1000), None, "credits").is_none());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn drops_caches_of_other_versions() {
+    let dir = cache_dir("moosync-cache-version");
+    let path = Path::new("/music/a.flac");
+    let cache = ScanCache::open(&dir, false).unwrap();
+    cache.put(path, 10, Some(1000), &song("Song"), "credits");
+    cache
+      .connection
+      .lock()
+      .unwrap()
+      .execute(format!("PRAGMA user_version = {}", CACHE_VERSION - 1))
+      .unwrap();
+    drop(cache);
+
+    let cache = ScanCache::open(&dir, false).unwrap();
+    assert!(cache.get(path, 10, Some(1000), None, "credits").is_none());
+    assert!(cache.take_warnings().is_empty());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn skips_entries_whose_covers_are_gone() {
+    let dir = cache_dir("moosync-cache-covers");
+    let path = Path::new("/music/a.flac");
+    let cover = dir.join("cover.jpg");
+    fs::write(&cover, "").unwrap();
+
+    let cache = ScanCache::open(&dir, false).unwrap();
+    cache.put(
+      path,
+      10,
+      Some(1000),
+      &Song {
+        high_path: Some(cover.to_string_lossy().to_string()),
+        ..song("Song")
+      },
+      "credits",
+    );
+    assert!(cache.get(path, 10, Some(1000), None, "credits").is_some());
+
+    fs::remove_file(&cover).unwrap();
+    assert!(cache.get(path, 10, Some(1000), None, "credits").is_none());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+}
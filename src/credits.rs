@@ -0,0 +1,411 @@
+use std::collections::HashSet;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::{error::ScanError, structs::ArtistCredits, utils::normalize_key};
+
+const FEATURED_TOKENS: [&str; 6] = ["featuring", "feat.", "feat", "ft.", "ft", "with"];
+const JOINT_TOKENS: [&str; 5] = ["&", ",", "x", "vs.", "vs"];
+
+// Words which end up in front of "Remix" without naming anyone
+const REMIX_STYLES: [&str; 10] = [
+  "album",
+  "club",
+  "dub",
+  "extended",
+  "instrumental",
+  "official",
+  "original",
+  "radio",
+  "single",
+  "vip",
+];
+
+pub const PRIMARY: &str = "primary";
+pub const FEATURED: &str = "featured";
+pub const REMIXER: &str = "remixer";
+
+/// Alternation of `tokens`. Tokens made of letters only count with whitespace
+/// around them, so `x` doesn't split "Alex" and "with" doesn't split "Within".
+fn token_pattern(tokens: &[String]) -> String {
+  let mut tokens: Vec<&String> = tokens.iter().filter(|t| !t.trim().is_empty()).collect();
+  tokens.sort_by_key(|token| std::cmp::Reverse(token.len()));
+
+  tokens
+    .iter()
+    .map(|token| {
+      let token = token.trim();
+      if token.chars().any(char::is_alphanumeric) {
+        format!(r"\s+{}\s+", regex::escape(token))
+      } else {
+        format!(r"\s*{}\s*", regex::escape(token))
+      }
+    })
+    .collect::<Vec<_>>()
+    .join("|")
+}
+
+/// Whatever stands in front of "Remix" names someone, rather than a year as
+/// in "2019 Remix" or a style as in "Extended Club Remix"
+fn names_remixer(capture: &str) -> bool {
+  let key = normalize_key(capture);
+  !key.chars().all(|c| c.is_ascii_digit() || c.is_whitespace())
+    && !key
+      .split(|c: char| !c.is_alphanumeric())
+      .any(|word| REMIX_STYLES.contains(&word))
+}
+
+fn case_insensitive(pattern: &str) -> Result<Option<Regex>, ScanError> {
+  if pattern.is_empty() {
+    return Ok(None);
+  }
+  Ok(Some(Regex::new(&format!("(?i){}", pattern))?))
+}
+
+#[derive(Debug)]
+struct Credits {
+  protected: Option<Regex>,
+  featured: Option<Regex>,
+  /// `(feat. X)` and `[with X]` anywhere in a string
+  featured_bracket: Option<Regex>,
+  joint: Option<Regex>,
+  from_title: bool,
+}
+
+/// Turns artist tags and titles into credited artists with their roles.
+///
+/// Without `ScanOptions.artistCredits` this only splits on `artistSplit`.
+#[derive(Debug)]
+pub struct CreditParser {
+  artist_split: String,
+  credits: Option<Credits>,
+  /// Identifies the settings, so cached songs parsed differently aren't served
+  key: String,
+}
+
+impl CreditParser {
+  pub fn new(artist_split: &str, options: Option<&ArtistCredits>) -> Result<Self, ScanError> {
+    let Some(options) = options else {
+      return Ok(Self {
+        artist_split: artist_split.to_string(),
+        credits: None,
+        key: artist_split.to_string(),
+      });
+    };
+
+    let defaults = |tokens: &[&str]| tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    let featured = options
+      .featured_tokens
+      .clone()
+      .unwrap_or_else(|| defaults(&FEATURED_TOKENS));
+    let joint = options
+      .joint_tokens
+      .clone()
+      .unwrap_or_else(|| defaults(&JOINT_TOKENS));
+    let protected = options.protected.clone().unwrap_or_default();
+
+    let mut joint_pattern = token_pattern(&joint);
+    if !artist_split.is_empty() {
+      if !joint_pattern.is_empty() {
+        joint_pattern.push('|');
+      }
+      joint_pattern.push_str(&regex::escape(artist_split));
+    }
+
+    let featured_words = featured
+      .iter()
+      .map(|token| token.trim())
+      .filter(|token| !token.is_empty())
+      .map(regex::escape)
+      .collect::<Vec<_>>()
+      .join("|");
+    let featured_bracket = if featured_words.is_empty() {
+      String::new()
+    } else {
+      format!(r"\s*[(\[]\s*(?:{})\s+([^()\[\]]+?)\s*[)\]]", featured_words)
+    };
+
+    let protected_pattern = protected
+      .iter()
+      .map(|name| name.trim())
+      .filter(|name| !name.is_empty())
+      .map(regex::escape)
+      .collect::<Vec<_>>()
+      .join("|");
+
+    Ok(Self {
+      artist_split: artist_split.to_string(),
+      credits: Some(Credits {
+        protected: case_insensitive(&protected_pattern)?,
+        featured: case_insensitive(&token_pattern(&featured))?,
+        featured_bracket: case_insensitive(&featured_bracket)?,
+        joint: case_insensitive(&joint_pattern)?,
+        from_title: options.from_title.unwrap_or(true),
+      }),
+      key: format!(
+        "{}\n{:?}\n{:?}\n{:?}\n{}",
+        artist_split,
+        featured,
+        joint,
+        protected,
+        options.from_title.unwrap_or(true)
+      ),
+    })
+  }
+
+  /// Whether parsed roles mean anything, or every name is a plain tag artist
+  pub fn roles(&self) -> bool {
+    self.credits.is_some()
+  }
+
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+
+  /// Joins names back into a single tag value
+  pub fn join(&self, names: &[String]) -> String {
+    if self.artist_split.is_empty() {
+      names.join(", ")
+    } else {
+      names.join(&self.artist_split)
+    }
+  }
+
+  /// Splits `value` on every match of `separator` which doesn't fall inside a
+  /// protected name
+  fn split(&self, value: &str, separator: &Regex) -> Vec<String> {
+    let protected: Vec<(usize, usize)> = self
+      .credits
+      .as_ref()
+      .and_then(|credits| credits.protected.as_ref())
+      .map(|regex| {
+        regex
+          .find_iter(value)
+          .map(|m| (m.start(), m.end()))
+          .collect()
+      })
+      .unwrap_or_default();
+
+    let mut parts = vec![];
+    let mut start = 0;
+    for m in separator.find_iter(value) {
+      if protected
+        .iter()
+        .any(|(from, to)| m.start() < *to && m.end() > *from)
+      {
+        continue;
+      }
+      parts.push(value[start..m.start()].to_string());
+      start = m.end();
+    }
+    parts.push(value[start..].to_string());
+    parts
+  }
+
+  fn split_joint(&self, value: &str) -> Vec<String> {
+    match self.credits.as_ref().and_then(|c| c.joint.as_ref()) {
+      Some(joint) => self.split(value, joint),
+      None => vec![value.to_string()],
+    }
+  }
+
+  /// Names in a single artist tag value, each with its role
+  fn parse_value(&self, value: &str, credited: &mut Vec<(String, &'static str)>) {
+    let Some(credits) = &self.credits else {
+      if self.artist_split.is_empty() {
+        credited.push((value.to_string(), PRIMARY));
+      } else {
+        for name in value.split(self.artist_split.as_str()) {
+          credited.push((name.to_string(), PRIMARY));
+        }
+      }
+      return;
+    };
+
+    let mut value = value.to_string();
+    let mut featured = vec![];
+    if let Some(bracket) = &credits.featured_bracket {
+      for captures in bracket.captures_iter(&value) {
+        featured.push(captures[1].to_string());
+      }
+      value = bracket.replace_all(&value, "").to_string();
+    }
+
+    let mut primary = value.clone();
+    if let Some(token) = &credits.featured {
+      let mut parts = self.split(&value, token).into_iter();
+      primary = parts.next().unwrap_or_default();
+      featured.extend(parts);
+    }
+
+    for name in self.split_joint(&primary) {
+      credited.push((name, PRIMARY));
+    }
+    for name in featured.iter().flat_map(|part| self.split_joint(part)) {
+      credited.push((name, FEATURED));
+    }
+  }
+
+  /// Featured artists and remixers credited in a title, such as
+  /// `Song (feat. X & Y)` or `Song (Z Remix)`
+  fn parse_title(&self, title: &str, credited: &mut Vec<(String, &'static str)>) {
+    lazy_static! {
+      static ref REMIX: Regex =
+        Regex::new(r"(?i)[(\[]\s*([^()\[\]]+?)\s+(?:re-?mix|rmx)\s*[)\]]").unwrap();
+      static ref REMIXED_BY: Regex =
+        Regex::new(r"(?i)[(\[]\s*(?:re-?mixed|remix)\s+by\s+([^()\[\]]+?)\s*[)\]]").unwrap();
+    }
+
+    let Some(credits) = self.credits.as_ref().filter(|c| c.from_title) else {
+      return;
+    };
+
+    if let Some(bracket) = &credits.featured_bracket {
+      for captures in bracket.captures_iter(title) {
+        for name in self.split_joint(&captures[1]) {
+          credited.push((name, FEATURED));
+        }
+      }
+    }
+
+    // "Remixed by" is always followed by a name
+    let remixes = REMIX
+      .captures_iter(title)
+      .filter(|captures| names_remixer(&captures[1]))
+      .chain(REMIXED_BY.captures_iter(title));
+    for captures in remixes {
+      for name in self.split_joint(&captures[1]) {
+        credited.push((name, REMIXER));
+      }
+    }
+  }
+
+  /// Credited artists in order: the artist tag's own names, then featured
+  /// artists, then remixers. A name credited twice keeps its first role.
+  pub fn parse<'a>(
+    &self,
+    artists: impl IntoIterator<Item = &'a str>,
+    remixers: impl IntoIterator<Item = &'a str>,
+    title: Option<&str>,
+  ) -> Vec<(String, &'static str)> {
+    let mut credited = vec![];
+    for value in artists.into_iter().flat_map(|value| value.split('\0')) {
+      self.parse_value(value, &mut credited);
+    }
+    if let Some(title) = title {
+      self.parse_title(title, &mut credited);
+    }
+    if self.credits.is_some() {
+      for value in remixers.into_iter().flat_map(|value| value.split('\0')) {
+        for name in self.split_joint(value) {
+          credited.push((name, REMIXER));
+        }
+      }
+    }
+
+    // Several tag values can each mix primary and featured artists
+    credited.sort_by_key(|(_, role)| match *role {
+      PRIMARY => 0,
+      FEATURED => 1,
+      _ => 2,
+    });
+
+    let mut seen = HashSet::new();
+    credited
+      .into_iter()
+      .map(|(name, role)| (name.trim().to_string(), role))
+      .filter(|(name, _)| !name.is_empty() && seen.insert(normalize_key(name)))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parser(protected: &[&str]) -> CreditParser {
+    let options = ArtistCredits {
+      protected: Some(protected.iter().map(|name| name.to_string()).collect()),
+      ..Default::default()
+    };
+    CreditParser::new(";", Some(&options)).unwrap()
+  }
+
+  fn expected(names: &[(&str, &'static str)]) -> Vec<(String, &'static str)> {
+    names
+      .iter()
+      .map(|(name, role)| (name.to_string(), *role))
+      .collect()
+  }
+
+  #[test]
+  fn keeps_protected_names_whole() {
+    let parsed = parser(&["Simon & Garfunkel"]).parse(["Simon & Garfunkel ft. Alex"], [], None);
+    assert_eq!(
+      parsed,
+      expected(&[("Simon & Garfunkel", PRIMARY), ("Alex", FEATURED)])
+    );
+  }
+
+  #[test]
+  fn splits_on_x_only_between_words() {
+    let parsed = parser(&[]).parse(["Alex x Maxx"], [], None);
+    assert_eq!(parsed, expected(&[("Alex", PRIMARY), ("Maxx", PRIMARY)]));
+
+    let parsed = parser(&[]).parse(["Xavier"], [], None);
+    assert_eq!(parsed, expected(&[("Xavier", PRIMARY)]));
+  }
+
+  #[test]
+  fn reads_bracketed_features() {
+    let parsed = parser(&[]).parse(["A (feat. B & C)"], [], Some("Song [with D]"));
+    assert_eq!(
+      parsed,
+      expected(&[
+        ("A", PRIMARY),
+        ("B", FEATURED),
+        ("C", FEATURED),
+        ("D", FEATURED)
+      ])
+    );
+  }
+
+  #[test]
+  fn reads_remixers_from_titles() {
+    let parser = parser(&[]);
+
+    let parsed = parser.parse(["A"], [], Some("Song (B Remix) [Remixed by C]"));
+    assert_eq!(
+      parsed,
+      expected(&[("A", PRIMARY), ("B", REMIXER), ("C", REMIXER)])
+    );
+
+    let parsed = parser.parse(["A"], ["D"], Some("Song (E & F Rmx)"));
+    assert_eq!(
+      parsed,
+      expected(&[
+        ("A", PRIMARY),
+        ("E", REMIXER),
+        ("F", REMIXER),
+        ("D", REMIXER)
+      ])
+    );
+  }
+
+  #[test]
+  fn skips_years_and_styles_before_remix() {
+    let parser = parser(&[]);
+    for title in [
+      "Song (2019 Remix)",
+      "Song (Extended Club Remix)",
+      "Song [Radio Edit Remix]",
+      "Song (Original Mix)",
+    ] {
+      assert_eq!(
+        parser.parse(["A"], [], Some(title)),
+        expected(&[("A", PRIMARY)])
+      );
+    }
+  }
+}
@@ -1,75 +1,1433 @@
-use std::{cmp::min, collections::HashSet, path::PathBuf};
+use std::{
+  collections::{HashMap, HashSet},
+  mem,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use sqlite3::{Connection, Error, State};
+use sqlite3::{Connection, Error, Readable, State, Statement, Type, Value};
 
-use crate::error::ScanError;
+use crate::{
+  error::ScanError,
+  structs::{DatabaseSchema, FileStat, Playlist, RemovedSong, Song},
+  utils::{hash_file, normalize_key, stable_id},
+};
 
-fn get_database(dir: PathBuf) -> Result<Connection, Error> {
+pub fn get_database(dir: PathBuf) -> Result<Connection, Error> {
   sqlite3::open(dir)
 }
 
-const EXPRESSION_LIMIT: usize = 998;
+/// How a file on disk differs from what the database has stored for it
+#[derive(Debug, Clone)]
+pub enum FileChange {
+  Added,
+  Modified { id: String },
+}
 
-pub fn files_not_in_db(
-  database: PathBuf,
-  file_list: Vec<(PathBuf, u64)>,
-) -> Result<Vec<(PathBuf, u64)>, ScanError> {
-  let hash_set: HashSet<(PathBuf, u64)> = file_list.into_iter().collect();
+fn quote(identifier: &str) -> String {
+  format!("\"{}\"", identifier.replace('"', "\"\""))
+}
 
-  let statement_raw = format!(
-    "SELECT path, size from allsongs WHERE {}",
-    "(path = ? AND size = ?) OR ".repeat(min(hash_set.len(), EXPRESSION_LIMIT))
-  );
+/// Whether `sql` is at most one statement. `Connection::prepare` compiles
+/// the first statement and silently drops the rest, so a `;` outside of
+/// literals, quoted names and comments may only be followed by whitespace.
+fn is_single_statement(sql: &str) -> bool {
+  let mut chars = sql.chars().peekable();
+  while let Some(c) = chars.next() {
+    let close = match c {
+      ';' => return chars.all(char::is_whitespace),
+      '\'' | '"' | '`' => c,
+      '[' => ']',
+      '-' if chars.peek() == Some(&'-') => '\n',
+      '/' if chars.peek() == Some(&'*') => {
+        chars.next();
+        while let Some(c) = chars.next() {
+          if c == '*' && chars.next_if_eq(&'/').is_some() {
+            break;
+          }
+        }
+        continue;
+      }
+      _ => continue,
+    };
+
+    // A doubled quote reopens the literal right after closing it
+    chars.find(|&c| c == close);
+  }
+
+  true
+}
+
+/// `DatabaseSchema` checked against the actual table. Optional columns the
+/// table doesn't have are `None`.
+#[derive(Debug)]
+struct ResolvedSchema {
+  table: String,
+  id: String,
+  path: String,
+  size: String,
+  mtime: Option<String>,
+  hash: Option<String>,
+  title: Option<String>,
+  duration: Option<String>,
+  filter: Option<String>,
+}
+
+impl ResolvedSchema {
+  fn new(connection: &Connection, schema: &DatabaseSchema) -> Result<Self, ScanError> {
+    let table = schema.table.clone().unwrap_or("allsongs".to_string());
+
+    let mut columns = HashSet::new();
+    let mut cursor = connection.prepare(format!("PRAGMA table_info({})", quote(&table)))?;
+    while let State::Row = cursor.next()? {
+      columns.insert(cursor.read::<String>(1)?.to_lowercase());
+    }
+
+    if columns.is_empty() {
+      return Err(ScanError::DatabaseSchema(format!(
+        "table {:?} does not exist",
+        table
+      )));
+    }
+
+    let missing = |column: &str| {
+      ScanError::DatabaseSchema(format!(
+        "column {:?} does not exist in table {:?}",
+        column, table
+      ))
+    };
 
-  let mut statement = statement_raw.chars();
+    // Explicitly mapped columns have to exist, default names are only used
+    // when the table has them
+    let optional = |mapped: &Option<String>, default: &str| match mapped {
+      Some(column) if columns.contains(&column.to_lowercase()) => Ok(Some(column.clone())),
+      Some(column) => Err(missing(column)),
+      None => Ok(columns.contains(default).then(|| default.to_string())),
+    };
+
+    let required = |mapped: &Option<String>, default: &str| {
+      optional(mapped, default)?.ok_or_else(|| missing(mapped.as_deref().unwrap_or(default)))
+    };
+
+    Ok(Self {
+      id: required(&schema.id_column, "_id")?,
+      path: required(&schema.path_column, "path")?,
+      size: required(&schema.size_column, "size")?,
+      mtime: optional(&schema.mtime_column, "mtime")?,
+      hash: optional(&schema.hash_column, "hash")?,
+      title: optional(&schema.title_column, "title")?,
+      duration: optional(&schema.duration_column, "duration")?,
+      filter: schema
+        .filter
+        .clone()
+        .filter(|filter| !filter.trim().is_empty()),
+      table,
+    })
+  }
+
+  /// Selects every mapped column under the names `scan_known` uses
+  fn select(&self) -> String {
+    let column = |column: &Option<String>, name: &str| match column {
+      Some(column) => format!("{} AS {}", quote(column), name),
+      None => format!("NULL AS {}", name),
+    };
+
+    format!(
+      "SELECT {} AS id, {} AS path, {} AS size, {}, {}, {}, {} FROM {} WHERE {} IS NOT NULL{}",
+      quote(&self.id),
+      quote(&self.path),
+      quote(&self.size),
+      column(&self.mtime, "mtime"),
+      column(&self.hash, "hash"),
+      column(&self.title, "title"),
+      column(&self.duration, "duration"),
+      quote(&self.table),
+      quote(&self.path),
+      self
+        .filter
+        .as_ref()
+        .map(|filter| format!(" AND ({})", filter))
+        .unwrap_or_default(),
+    )
+  }
+}
+
+fn read_optional<T: Readable>(cursor: &Statement, i: usize) -> Result<Option<T>, Error> {
+  match cursor.kind(i) {
+    Type::Null => Ok(None),
+    _ => cursor.read::<T>(i).map(Some),
+  }
+}
+
+#[derive(Debug)]
+struct StoredSong {
+  id: String,
+  size: u64,
+  mtime: Option<u64>,
+  hash: Option<String>,
+}
+
+/// Snapshot of the song table in an indexed temp table. Each chunk of
+/// discovered files goes into a second temp table and is looked up with a
+/// single join.
+pub struct SongIndex {
+  connection: Connection,
+}
+
+impl SongIndex {
+  /// Checks `schema` against the database before taking the snapshot, so a
+  /// wrong table, column or filter fails with `ScanError::DatabaseSchema`.
+  pub fn new(connection: Connection, schema: &DatabaseSchema) -> Result<Self, ScanError> {
+    let select = ResolvedSchema::new(&connection, schema)?.select();
+    let invalid_filter = |message: String| {
+      ScanError::DatabaseSchema(format!(
+        "invalid filter {:?}: {}",
+        schema.filter.as_deref().unwrap_or_default(),
+        message
+      ))
+    };
+    if let Err(e) = connection.prepare(&select) {
+      return Err(invalid_filter(e.message.unwrap_or_default()));
+    }
+    if !is_single_statement(&select) {
+      return Err(invalid_filter("must be a single expression".to_string()));
+    }
 
-  statement.next_back();
-  statement.next_back();
-  statement.next_back();
+    // Only the snapshot query carries caller text, and it runs as a single
+    // prepared statement. Everything else is fixed.
+    connection.execute("DROP TABLE IF EXISTS temp.scan_known")?;
+    connection
+      .prepare(format!("CREATE TEMP TABLE scan_known AS {}", select))?
+      .next()?;
+    connection.execute(
+      "CREATE INDEX temp.scan_known_path ON scan_known (path);
+      DROP TABLE IF EXISTS temp.scan_candidates;
+      CREATE TEMP TABLE scan_candidates (idx INTEGER PRIMARY KEY, path TEXT NOT NULL);",
+    )?;
 
-  let mut result: HashSet<(PathBuf, u64)> = HashSet::new();
+    Ok(Self { connection })
+  }
 
-  let connection = get_database(database)?;
+  fn stored_songs(&self, files: &[FileStat]) -> Result<Vec<Option<StoredSong>>, ScanError> {
+    self.connection.execute("DELETE FROM scan_candidates")?;
 
-  let mut cursor = connection.prepare(statement.as_str())?;
+    let mut insert = self
+      .connection
+      .prepare("INSERT INTO scan_candidates (idx, path) VALUES (?, ?)")?;
+    for (idx, file) in files.iter().enumerate() {
+      insert.reset()?;
+      insert.bind(1, idx as i64)?;
+      insert.bind(2, file.path.to_string_lossy().as_ref())?;
+      insert.next()?;
+    }
 
-  let mut i = 1;
-  for (path, size) in hash_set.iter() {
-    cursor.bind(
-      i,
-      dunce::canonicalize(path)?
-        .to_string_lossy()
-        .to_string()
-        .as_str(),
+    let mut stored: Vec<Option<StoredSong>> = (0..files.len()).map(|_| None).collect();
+    let mut cursor = self.connection.prepare(
+      "SELECT c.idx, k.id, k.size, k.mtime, k.hash
+      FROM scan_candidates c JOIN scan_known k ON k.path = c.path",
     )?;
-    cursor.bind(i + 1, *size as f64)?;
+    while let State::Row = cursor.next()? {
+      let idx = cursor.read::<i64>(0)? as usize;
+      if stored[idx].is_some() {
+        continue;
+      }
+
+      stored[idx] = Some(StoredSong {
+        id: cursor.read::<String>(1)?,
+        size: read_optional::<i64>(&cursor, 2)?.unwrap_or_default() as u64,
+        mtime: read_optional::<i64>(&cursor, 3)?.map(|mtime| mtime as u64),
+        hash: read_optional::<String>(&cursor, 4)?,
+      });
+    }
+
+    Ok(stored)
+  }
+
+  /// Compares files found on disk against the song table. Unchanged files are
+  /// left out. With `hash_content` set, files matching on size and mtime are
+  /// also hashed and compared against the stored hash.
+  ///
+  /// Paths are expected to be canonical already, as discovery produces them.
+  pub fn diff(
+    &self,
+    files: Vec<FileStat>,
+    hash_content: bool,
+  ) -> Result<Vec<(FileStat, FileChange)>, ScanError> {
+    self.connection.execute("BEGIN")?;
+    let stored = self.stored_songs(&files);
+    self
+      .connection
+      .execute(if stored.is_ok() { "COMMIT" } else { "ROLLBACK" })?;
+
+    let mut changes = vec![];
+    for (file, song) in files.into_iter().zip(stored?) {
+      let Some(song) = song else {
+        changes.push((file, FileChange::Added));
+        continue;
+      };
+
+      let modified = song.size != file.size
+        || matches!((song.mtime, file.mtime), (Some(stored), Some(current)) if stored != current)
+        || match &song.hash {
+          Some(stored) if hash_content => {
+            hash_file(&file.path).is_ok_and(|current| &current != stored)
+          }
+          _ => false,
+        };
+
+      if modified {
+        changes.push((file, FileChange::Modified { id: song.id }));
+      }
+    }
+
+    Ok(changes)
+  }
+
+  /// Collects rows below `roots` whose file is gone
+  pub fn missing_songs(&self, roots: &[PathBuf]) -> Result<Vec<MissingSong>, ScanError> {
+    let roots: Vec<PathBuf> = roots
+      .iter()
+      .filter_map(|root| dunce::canonicalize(root).ok())
+      .collect();
+
+    let mut missing = vec![];
+    let mut cursor = self
+      .connection
+      .prepare("SELECT id, path, size, hash, title, duration FROM scan_known")?;
+    while let State::Row = cursor.next()? {
+      let path = PathBuf::from(cursor.read::<String>(1)?);
+      if !roots.iter().any(|root| path.starts_with(root)) || path.exists() {
+        continue;
+      }
+
+      missing.push(MissingSong {
+        id: cursor.read::<String>(0)?,
+        path: path.to_string_lossy().to_string(),
+        size: read_optional::<i64>(&cursor, 2)?.unwrap_or_default() as u64,
+        hash: read_optional::<String>(&cursor, 3)?,
+        title: read_optional::<String>(&cursor, 4)?,
+        duration: read_optional::<f64>(&cursor, 5)?,
+        album: None,
+        artists: vec![],
+      });
+    }
+
+    for song in missing.iter_mut().filter(|song| song.hash.is_none()) {
+      song.album = self.linked_names(ALBUM_NAMES, &song.id).pop();
+      song.artists = self.linked_names(ARTIST_NAMES, &song.id);
+    }
+
+    Ok(missing)
+  }
+
+  /// Reads album or artist names linked to a row. Tables with their own
+  /// layout have no bridges, so their rows have none.
+  fn linked_names(&self, sql: &str, id: &str) -> Vec<String> {
+    let Ok(mut cursor) = self.connection.prepare(sql) else {
+      return vec![];
+    };
+
+    let mut names = vec![];
+    if cursor.bind(1, id).is_ok() {
+      while let Ok(State::Row) = cursor.next() {
+        names.extend(cursor.read::<String>(0));
+      }
+    }
+    names
+  }
+}
 
-    i += 2;
+const ALBUM_NAMES: &str = "SELECT album_name FROM album_bridge
+  JOIN albums ON album_id = album WHERE song = ?";
+const ARTIST_NAMES: &str = "SELECT artist_name FROM artists_bridge
+  JOIN artists ON artist_id = artist WHERE song = ?";
 
-    if i == (EXPRESSION_LIMIT * 2) + 1 {
+/// Album or artist names compared the way their ids are derived
+fn name_keys<'a>(names: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+  let mut keys: Vec<String> = names
+    .into_iter()
+    .map(|name| normalize_key(name))
+    .filter(|key| !key.is_empty())
+    .collect();
+  keys.sort();
+  keys.dedup();
+  keys
+}
+
+/// A row below the scanned folders whose file is gone. It might have moved
+/// and still turn up among the added files.
+#[derive(Debug, Clone)]
+pub struct MissingSong {
+  pub id: String,
+  pub path: String,
+  size: u64,
+  hash: Option<String>,
+  title: Option<String>,
+  duration: Option<f64>,
+  album: Option<String>,
+  artists: Vec<String>,
+}
+
+impl MissingSong {
+  fn matches_tags(&self, song: &Song) -> bool {
+    let same_title = match (&self.title, &song.title) {
+      (Some(a), Some(b)) => !a.trim().is_empty() && a.trim().eq_ignore_ascii_case(b.trim()),
+      _ => false,
+    };
+
+    let same_duration = match (self.duration, song.duration) {
+      (Some(a), Some(b)) if a > 0.0 && b > 0.0 => (a - b).abs() <= 1.0,
+      _ => Some(self.size) == song.size.map(|size| size as u64),
+    };
+
+    // Untagged songs share a title often enough, so these have to agree too
+    let same_names =
+      |stored: Vec<String>, current: Vec<String>| !stored.is_empty() && stored == current;
+    let same_album = same_names(
+      name_keys(&self.album),
+      name_keys(song.album.as_ref().map(|album| &album.album_name)),
+    );
+    let same_artists = same_names(
+      name_keys(&self.artists),
+      name_keys(song.artists.iter().map(|artist| &artist.artist_name)),
+    );
+
+    same_title && same_duration && same_album && same_artists
+  }
+}
+
+/// Rows whose file is gone, shared with the pool so added files can claim
+/// them as moves. Whatever is left once the scan is done was removed.
+#[derive(Debug, Default, Clone)]
+pub struct MissingSongs(Arc<Mutex<Vec<MissingSong>>>);
+
+impl MissingSongs {
+  pub fn set(&self, songs: Vec<MissingSong>) {
+    *self.0.lock().unwrap() = songs;
+  }
+
+  /// Matches an added file by the hash of its audio, so a file retagged on
+  /// its way to a new path still counts as moved. Files are only hashed
+  /// while some missing row has a stored hash.
+  pub fn claim_by_hash(&self, path: &Path, hash: Option<&str>) -> Option<MissingSong> {
+    let candidates = self
+      .0
+      .lock()
+      .unwrap()
+      .iter()
+      .any(|missing| missing.hash.is_some());
+    if !candidates {
+      return None;
+    }
+
+    let hash = match hash {
+      Some(hash) => hash.to_string(),
+      None => hash_file(path).ok()?,
+    };
+    self.claim(|missing| missing.hash.as_ref() == Some(&hash))
+  }
+
+  /// Matches an added song by title, duration, album and artists, for rows
+  /// without a hash
+  pub fn claim_by_tags(&self, song: &Song) -> Option<MissingSong> {
+    self.claim(|missing| missing.hash.is_none() && missing.matches_tags(song))
+  }
+
+  fn claim(&self, predicate: impl Fn(&MissingSong) -> bool) -> Option<MissingSong> {
+    let mut songs = self.0.lock().unwrap();
+    let index = songs.iter().position(predicate)?;
+    Some(songs.swap_remove(index))
+  }
+
+  pub fn take_removed(&self) -> Vec<RemovedSong> {
+    mem::take(&mut *self.0.lock().unwrap())
+      .into_iter()
+      .map(|missing| RemovedSong {
+        _id: missing.id,
+        path: missing.path,
+      })
+      .collect()
+  }
+}
+
+// Written rows are committed in groups of this size, or once a group has been
+// open for a second
+const WRITE_BATCH: usize = 500;
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tables the writer fills, in the layout of Moosync's `songs.db`. Missing
+/// tables are created. Existing ones get whichever of these columns they have.
+const WRITER_TABLES: &str = "
+  CREATE TABLE IF NOT EXISTS allsongs (
+    _id TEXT PRIMARY KEY, path TEXT, size INTEGER, mtime INTEGER, hash TEXT, title TEXT,
+    bitrate INTEGER, sampleRate INTEGER, duration REAL, container TEXT, year TEXT,
+    lyrics TEXT, track_no TEXT, type TEXT, playbackUrl TEXT, song_coverPath_high TEXT,
+    song_coverPath_low TEXT, date_added INTEGER
+  );
+  CREATE TABLE IF NOT EXISTS albums (
+    album_id TEXT PRIMARY KEY, album_name TEXT, album_artist TEXT, album_coverPath_high TEXT,
+    album_coverPath_low TEXT, album_song_count INTEGER DEFAULT 0
+  );
+  CREATE TABLE IF NOT EXISTS album_bridge (id INTEGER PRIMARY KEY AUTOINCREMENT, song TEXT, album TEXT);
+  CREATE TABLE IF NOT EXISTS artists (
+    artist_id TEXT PRIMARY KEY, artist_name TEXT, artist_song_count INTEGER DEFAULT 0
+  );
+  CREATE TABLE IF NOT EXISTS artists_bridge (id INTEGER PRIMARY KEY AUTOINCREMENT, song TEXT, artist TEXT);
+  CREATE TABLE IF NOT EXISTS genres (
+    genre_id TEXT PRIMARY KEY, genre_name TEXT, genre_song_count INTEGER DEFAULT 0
+  );
+  CREATE TABLE IF NOT EXISTS genre_bridge (id INTEGER PRIMARY KEY AUTOINCREMENT, song TEXT, genre TEXT);
+  CREATE TABLE IF NOT EXISTS playlists (
+    playlist_id TEXT PRIMARY KEY, playlist_name TEXT, playlist_path TEXT,
+    playlist_song_count INTEGER DEFAULT 0
+  );
+  CREATE TABLE IF NOT EXISTS playlist_bridge (id INTEGER PRIMARY KEY AUTOINCREMENT, song TEXT, playlist TEXT);
+";
+
+const SONG_BRIDGES: [&str; 4] = [
+  "album_bridge",
+  "artists_bridge",
+  "genre_bridge",
+  "playlist_bridge",
+];
+
+fn text(value: &Option<String>) -> Value {
+  value.clone().map(Value::String).unwrap_or(Value::Null)
+}
+
+fn integer(value: Option<i64>) -> Value {
+  value.map(Value::Integer).unwrap_or(Value::Null)
+}
+
+/// Ids of the rows committed since the last commit
+#[derive(Debug, Default)]
+pub struct Written {
+  pub songs: Vec<String>,
+  pub playlists: Vec<String>,
+}
+
+/// Writes songs, albums, artists, genres and playlists straight into the
+/// database, in batched transactions
+pub struct DatabaseWriter {
+  connection: Connection,
+  columns: HashMap<String, HashSet<String>>,
+  /// Playlist ids handed out by the scan, mapped to the ids of their rows
+  playlists: HashMap<String, String>,
+  written: Written,
+  pending: usize,
+  opened: Instant,
+}
+
+impl DatabaseWriter {
+  pub fn open(path: PathBuf) -> Result<Self, ScanError> {
+    if path.as_os_str().is_empty() {
+      return Err(ScanError::String(
+        "Writing to the database needs a databaseDir".to_string(),
+      ));
+    }
+
+    let mut connection = get_database(path)?;
+    connection.set_busy_timeout(5000)?;
+    connection.execute("PRAGMA journal_mode = WAL")?;
+    connection.execute(WRITER_TABLES)?;
+
+    let mut columns = HashMap::new();
+    for table in ["allsongs", "albums", "artists", "genres", "playlists"] {
+      let mut table_columns = HashSet::new();
+      let mut cursor = connection.prepare(format!("PRAGMA table_info({})", table))?;
       while let State::Row = cursor.next()? {
-        let path = cursor.read::<String>(0)?;
-        let size = cursor.read::<i64>(1)?;
+        table_columns.insert(cursor.read::<String>(1)?);
+      }
+      columns.insert(table.to_string(), table_columns);
+    }
+
+    Ok(Self {
+      connection,
+      columns,
+      playlists: HashMap::new(),
+      written: Written::default(),
+      pending: 0,
+      opened: Instant::now(),
+    })
+  }
+
+  fn has(&self, table: &str, column: &str) -> bool {
+    self
+      .columns
+      .get(table)
+      .is_some_and(|columns| columns.contains(column))
+  }
+
+  fn execute(&self, sql: &str, values: &[Value]) -> Result<(), ScanError> {
+    let mut statement = self.connection.prepare(sql)?;
+    for (i, value) in values.iter().enumerate() {
+      statement.bind(i + 1, value)?;
+    }
+    while let State::Row = statement.next()? {}
+
+    Ok(())
+  }
+
+  fn find(
+    &self,
+    table: &str,
+    id: &str,
+    key: &str,
+    value: &str,
+  ) -> Result<Option<String>, ScanError> {
+    let mut cursor = self.connection.prepare(format!(
+      "SELECT {} FROM {} WHERE {} = ? LIMIT 1",
+      id, table, key
+    ))?;
+    cursor.bind(1, value)?;
+
+    Ok(match cursor.next()? {
+      State::Row => Some(cursor.read::<String>(0)?),
+      State::Done => None,
+    })
+  }
+
+  /// Updates the row with the given id, or inserts it along with
+  /// `insert_only`. Columns the table doesn't have are left out.
+  fn upsert(
+    &self,
+    table: &str,
+    (key, id): (&str, &str),
+    values: Vec<(&str, Value)>,
+    insert_only: Vec<(&str, Value)>,
+  ) -> Result<(), ScanError> {
+    let exists = self.find(table, key, key, id)?.is_some();
+    let (names, mut values): (Vec<&str>, Vec<Value>) = values
+      .into_iter()
+      .chain(insert_only.into_iter().filter(|_| !exists))
+      .filter(|(column, _)| self.has(table, column))
+      .unzip();
+
+    if exists {
+      if names.is_empty() {
+        return Ok(());
+      }
+
+      let set: Vec<String> = names.iter().map(|name| format!("{} = ?", name)).collect();
+      values.push(Value::String(id.to_string()));
+      self.execute(
+        &format!("UPDATE {} SET {} WHERE {} = ?", table, set.join(", "), key),
+        &values,
+      )
+    } else {
+      values.insert(0, Value::String(id.to_string()));
+      self.execute(
+        &format!(
+          "INSERT INTO {} ({}) VALUES ({})",
+          table,
+          [key]
+            .iter()
+            .chain(&names)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", "),
+          vec!["?"; values.len()].join(", ")
+        ),
+        &values,
+      )
+    }
+  }
+
+  /// Finds an album, artist or genre by its stable id, then by name among
+  /// rows whose `matching` columns agree, inserting it if it's new
+  fn linked(
+    &self,
+    table: &str,
+    (key, id): (&str, &str),
+    (column, name): (&str, &str),
+    matching: Vec<(&str, Value)>,
+    values: Vec<(&str, Value)>,
+  ) -> Result<String, ScanError> {
+    if self.find(table, key, key, id)?.is_some() {
+      return Ok(id.to_string());
+    }
+
+    // Rows added by the app itself have ids of their own
+    let matching: Vec<(&str, Value)> = matching
+      .into_iter()
+      .filter(|(column, _)| self.has(table, column))
+      .collect();
+    let conditions: String = matching
+      .iter()
+      .map(|(column, _)| format!(" AND {} IS ?", column))
+      .collect();
+    let mut cursor = self.connection.prepare(format!(
+      "SELECT {} FROM {} WHERE {} = ?{} LIMIT 1",
+      key, table, column, conditions
+    ))?;
+    cursor.bind(1, name)?;
+    for (i, (_, value)) in matching.iter().enumerate() {
+      cursor.bind(i + 2, value)?;
+    }
+    if let State::Row = cursor.next()? {
+      return Ok(cursor.read::<String>(0)?);
+    }
+
+    let mut values = values;
+    values.extend(matching);
+    values.push((column, Value::String(name.to_string())));
+    self.upsert(table, (key, id), values, vec![])?;
+
+    Ok(id.to_string())
+  }
+
+  fn begin(&mut self) -> Result<(), ScanError> {
+    if self.pending == 0 {
+      self.connection.execute("BEGIN")?;
+      self.opened = Instant::now();
+    }
+    self.pending += 1;
+
+    Ok(())
+  }
+
+  /// Writes a song and its album, artists, genres and playlist link. A row
+  /// with the same path is updated in place. Returns the id of the row.
+  /// Nothing of a song which fails halfway is kept.
+  pub fn song(&mut self, song: &Song) -> Result<String, ScanError> {
+    self.begin()?;
+
+    self.connection.execute("SAVEPOINT song")?;
+    match self.write_song(song) {
+      Ok(id) => {
+        self.connection.execute("RELEASE song")?;
+        self.written.songs.push(id.clone());
+        Ok(id)
+      }
+      Err(e) => {
+        self
+          .connection
+          .execute("ROLLBACK TO song; RELEASE song")
+          .ok();
+        Err(e)
+      }
+    }
+  }
+
+  fn write_song(&self, song: &Song) -> Result<String, ScanError> {
+    let existing = match &song.path {
+      Some(path) if song.song_type == "LOCAL" => self.find("allsongs", "_id", "path", path)?,
+      _ => None,
+    };
+    let id = existing.unwrap_or(song._id.clone());
+
+    self.upsert(
+      "allsongs",
+      ("_id", &id),
+      vec![
+        ("path", text(&song.path)),
+        ("size", integer(song.size.map(i64::from))),
+        ("mtime", integer(song.mtime.map(|mtime| mtime as i64))),
+        ("hash", text(&song.hash)),
+        ("title", text(&song.title)),
+        ("bitrate", integer(song.bitrate.map(i64::from))),
+        ("sampleRate", integer(song.sample_rate.map(i64::from))),
+        (
+          "duration",
+          song.duration.map(Value::Float).unwrap_or(Value::Null),
+        ),
+        ("container", text(&song.container)),
+        ("year", text(&song.year)),
+        ("lyrics", text(&song.lyrics)),
+        ("track_no", text(&song.track_no)),
+        ("type", Value::String(song.song_type.clone())),
+        ("playbackUrl", text(&song.playback_url)),
+        ("song_coverPath_high", text(&song.high_path)),
+        ("song_coverPath_low", text(&song.low_path)),
+      ],
+      vec![(
+        "date_added",
+        integer(
+          SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|now| now.as_millis() as i64),
+        ),
+      )],
+    )?;
+
+    let playlist = song
+      .playlist_id
+      .as_ref()
+      .and_then(|playlist_id| self.playlists.get(playlist_id).cloned());
+
+    // Playlist links are only ever added, the rest is rebuilt from the tags
+    for bridge in &SONG_BRIDGES[..3] {
+      self.execute(
+        &format!("DELETE FROM {} WHERE song = ?", bridge),
+        &[Value::String(id.clone())],
+      )?;
+    }
+
+    if let Some(album) = &song.album {
+      let album_id = self.linked(
+        "albums",
+        ("album_id", &album.album_id),
+        ("album_name", &album.album_name),
+        vec![("album_artist", text(&album.album_artist))],
+        vec![
+          ("album_coverPath_high", text(&album.album_cover_path_high)),
+          ("album_coverPath_low", text(&album.album_cover_path_low)),
+        ],
+      )?;
+      self.execute(
+        "INSERT INTO album_bridge (song, album) VALUES (?, ?)",
+        &[Value::String(id.clone()), Value::String(album_id)],
+      )?;
+    }
+
+    for artist in &song.artists {
+      let artist_id = self.linked(
+        "artists",
+        ("artist_id", &artist.artist_id),
+        ("artist_name", &artist.artist_name),
+        vec![],
+        vec![],
+      )?;
+      self.execute(
+        "INSERT INTO artists_bridge (song, artist) VALUES (?, ?)",
+        &[Value::String(id.clone()), Value::String(artist_id)],
+      )?;
+    }
+
+    for genre in song.genre.iter().flatten() {
+      let genre_id = self.linked(
+        "genres",
+        ("genre_id", &stable_id("genre", &normalize_key(genre))),
+        ("genre_name", genre),
+        vec![],
+        vec![],
+      )?;
+      self.execute(
+        "INSERT INTO genre_bridge (song, genre) VALUES (?, ?)",
+        &[Value::String(id.clone()), Value::String(genre_id)],
+      )?;
+    }
+
+    if let Some(playlist) = playlist {
+      self.execute(
+        "INSERT INTO playlist_bridge (song, playlist)
+        SELECT ?1, ?2 WHERE NOT EXISTS (SELECT 1 FROM playlist_bridge WHERE song = ?1 AND playlist = ?2)",
+        &[Value::String(id.clone()), Value::String(playlist)],
+      )?;
+    }
+
+    Ok(id)
+  }
+
+  /// Writes a playlist. A row with the same path is reused, so songs found
+  /// through it get linked to that row.
+  pub fn playlist(&mut self, playlist: &Playlist) -> Result<String, ScanError> {
+    self.begin()?;
+
+    let id = self
+      .find("playlists", "playlist_id", "playlist_path", &playlist.path)?
+      .unwrap_or(playlist.id.clone());
+
+    self.upsert(
+      "playlists",
+      ("playlist_id", &id),
+      vec![
+        ("playlist_name", Value::String(playlist.title.clone())),
+        ("playlist_path", Value::String(playlist.path.clone())),
+      ],
+      vec![],
+    )?;
+
+    self.playlists.insert(playlist.id.clone(), id.clone());
+    self.written.playlists.push(id.clone());
+    Ok(id)
+  }
+
+  /// Deletes rows whose file is gone, along with their links
+  pub fn remove(&mut self, songs: &[RemovedSong]) -> Result<(), ScanError> {
+    for song in songs {
+      self.begin()?;
 
-        result.insert((PathBuf::from(path), size as u64));
+      let id = [Value::String(song._id.clone())];
+      self.execute("DELETE FROM allsongs WHERE _id = ?", &id)?;
+      for bridge in SONG_BRIDGES {
+        self.execute(&format!("DELETE FROM {} WHERE song = ?", bridge), &id)?;
       }
+    }
+
+    Ok(())
+  }
+
+  /// Whether the open transaction is big or old enough to be committed
+  pub fn due(&self) -> bool {
+    self.pending >= WRITE_BATCH || (self.pending > 0 && self.opened.elapsed() >= WRITE_INTERVAL)
+  }
+
+  /// Commits the open transaction. If that fails it is rolled back instead,
+  /// so that the next write starts a fresh one.
+  pub fn commit(&mut self) -> Result<Written, ScanError> {
+    if self.pending > 0 {
+      if let Err(e) = self.connection.execute("COMMIT") {
+        self.connection.execute("ROLLBACK").ok();
+        self.pending = 0;
+        self.written = Written::default();
+        return Err(e.into());
+      }
+      self.pending = 0;
+    }
 
-      i = 1;
-      cursor = connection.prepare(statement.as_str())?;
-      continue;
+    Ok(mem::take(&mut self.written))
+  }
+
+  /// Commits what's left and brings the song counts up to date
+  pub fn finish(mut self) -> Result<Written, ScanError> {
+    self.begin()?;
+    for (table, id, bridge, column) in [
+      ("albums", "album_id", "album_bridge", "album"),
+      ("artists", "artist_id", "artists_bridge", "artist"),
+      ("genres", "genre_id", "genre_bridge", "genre"),
+      ("playlists", "playlist_id", "playlist_bridge", "playlist"),
+    ] {
+      let count = format!("{}_song_count", table.trim_end_matches('s'));
+      if self.has(table, &count) {
+        self.execute(
+          &format!(
+            "UPDATE {table} SET {count} = (SELECT COUNT(*) FROM {bridge} WHERE {column} = {table}.{id})"
+          ),
+          &[],
+        )?;
+      }
     }
+
+    self.commit()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs, time::Instant};
+
+  use super::*;
+  use crate::structs::{Album, Artists};
+
+  fn writer() -> DatabaseWriter {
+    DatabaseWriter::open(PathBuf::from(":memory:")).unwrap()
   }
 
-  while let State::Row = cursor.next()? {
-    let path = cursor.read::<String>(0)?;
-    let size = cursor.read::<i64>(1)?;
+  fn local_song(id: &str, path: &str, title: &str) -> Song {
+    Song {
+      _id: id.to_string(),
+      path: Some(path.to_string()),
+      title: Some(title.to_string()),
+      song_type: "LOCAL".to_string(),
+      ..Default::default()
+    }
+  }
 
-    result.insert((PathBuf::from(path), size as u64));
+  fn artist(name: &str) -> Artists {
+    Artists {
+      artist_id: stable_id("artist", &normalize_key(name)),
+      artist_name: name.to_string(),
+      ..Default::default()
+    }
   }
 
-  let diff = hash_set.difference(&result).clone();
+  fn strings(writer: &DatabaseWriter, sql: &str) -> Vec<String> {
+    let mut cursor = writer.connection.prepare(sql).unwrap();
+    let mut rows = vec![];
+    while let State::Row = cursor.next().unwrap() {
+      rows.push(cursor.read::<String>(0).unwrap());
+    }
+    rows
+  }
 
-  let vec = diff.into_iter().map(|v| v.clone()).collect();
+  fn song_table() -> Connection {
+    let connection = sqlite3::open(":memory:").unwrap();
+    connection
+      .execute(
+        "CREATE TABLE allsongs (_id TEXT PRIMARY KEY, path TEXT, size INTEGER, title TEXT);
+        INSERT INTO allsongs VALUES ('plain', '/music/a.flac', 1, 'Plain');
+        INSERT INTO allsongs VALUES ('semicolon', '/music/b.flac', 1, 'Semi;colon');",
+      )
+      .unwrap();
+    connection
+  }
 
-  Ok(vec)
+  fn schema_error(schema: DatabaseSchema) -> String {
+    match SongIndex::new(song_table(), &schema) {
+      Err(ScanError::DatabaseSchema(message)) => message,
+      Err(e) => panic!("unexpected error {}", e),
+      Ok(_) => panic!("schema {:?} was accepted", schema),
+    }
+  }
+
+  #[test]
+  fn finds_single_statements() {
+    assert!(is_single_statement("SELECT 1"));
+    assert!(is_single_statement("SELECT 1;  "));
+    assert!(is_single_statement(
+      "SELECT ';', \"a;b\", [c;d], `e;f` -- ;\n"
+    ));
+    assert!(is_single_statement("SELECT 'it''s; fine' /* ; */"));
+    assert!(!is_single_statement("SELECT 1; DROP TABLE allsongs"));
+    assert!(!is_single_statement("SELECT 'a''b'; SELECT 2"));
+    assert!(!is_single_statement("SELECT 1 /* */; SELECT 2"));
+  }
+
+  #[test]
+  fn rejects_missing_tables_and_columns() {
+    let message = schema_error(DatabaseSchema {
+      table: Some("songs".to_string()),
+      ..Default::default()
+    });
+    assert_eq!(message, r#"table "songs" does not exist"#);
+
+    let message = schema_error(DatabaseSchema {
+      hash_column: Some("checksum".to_string()),
+      ..Default::default()
+    });
+    assert_eq!(
+      message,
+      r#"column "checksum" does not exist in table "allsongs""#
+    );
+
+    let message = schema_error(DatabaseSchema {
+      size_column: Some("bytes".to_string()),
+      ..Default::default()
+    });
+    assert!(message.contains(r#""bytes""#));
+  }
+
+  #[test]
+  fn rejects_filters_with_more_than_one_statement() {
+    let message = schema_error(DatabaseSchema {
+      filter: Some("1); DROP TABLE allsongs; SELECT (1".to_string()),
+      ..Default::default()
+    });
+    assert!(message.ends_with("must be a single expression"));
+
+    let message = schema_error(DatabaseSchema {
+      filter: Some("no_such_column = 1".to_string()),
+      ..Default::default()
+    });
+    assert!(message.starts_with("invalid filter"));
+  }
+
+  #[test]
+  fn applies_filters_with_quoted_semicolons() {
+    let schema = DatabaseSchema {
+      filter: Some("title LIKE '%;%'".to_string()),
+      ..Default::default()
+    };
+    let index = SongIndex::new(song_table(), &schema).unwrap();
+    let files = ["/music/a.flac", "/music/b.flac"].map(|path| FileStat {
+      path: PathBuf::from(path),
+      size: 1,
+      mtime: None,
+    });
+
+    let changes = index.diff(files.to_vec(), false).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].0.path, PathBuf::from("/music/a.flac"));
+  }
+
+  #[test]
+  fn classifies_files_against_the_table() {
+    let root = dunce::canonicalize(env::temp_dir())
+      .unwrap()
+      .join("moosync-diff-test");
+    fs::remove_dir_all(&root).ok();
+    fs::create_dir_all(&root).unwrap();
+    for name in ["same.flac", "resized.flac", "touched.flac", "new.flac"] {
+      fs::write(root.join(name), name).unwrap();
+    }
+
+    let connection = sqlite3::open(":memory:").unwrap();
+    connection
+      .execute(
+        "CREATE TABLE allsongs (_id TEXT PRIMARY KEY, path TEXT, size INTEGER, mtime INTEGER)",
+      )
+      .unwrap();
+    for (id, name) in [
+      ("same", "same.flac"),
+      ("resized", "resized.flac"),
+      ("touched", "touched.flac"),
+      ("gone", "gone.flac"),
+    ] {
+      let mut insert = connection
+        .prepare("INSERT INTO allsongs VALUES (?, ?, 10, 1000)")
+        .unwrap();
+      insert.bind(1, id).unwrap();
+      insert
+        .bind(2, root.join(name).to_string_lossy().as_ref())
+        .unwrap();
+      insert.next().unwrap();
+    }
+
+    let index = SongIndex::new(connection, &DatabaseSchema::default()).unwrap();
+    let file = |name: &str, size: u64, mtime: u64| FileStat {
+      path: root.join(name),
+      size,
+      mtime: Some(mtime),
+    };
+    let changes: Vec<(String, String)> = index
+      .diff(
+        vec![
+          file("same.flac", 10, 1000),
+          file("resized.flac", 11, 1000),
+          file("touched.flac", 10, 2000),
+          file("new.flac", 10, 1000),
+        ],
+        false,
+      )
+      .unwrap()
+      .into_iter()
+      .map(|(file, change)| {
+        let name = file.path.file_name().unwrap().to_string_lossy().to_string();
+        match change {
+          FileChange::Added => (name, "added".to_string()),
+          FileChange::Modified { id } => (name, id),
+        }
+      })
+      .collect();
+    assert_eq!(
+      changes,
+      [
+        ("resized.flac", "resized"),
+        ("touched.flac", "touched"),
+        ("new.flac", "added"),
+      ]
+      .map(|(name, change)| (name.to_string(), change.to_string()))
+    );
+
+    let missing = index.missing_songs(std::slice::from_ref(&root)).unwrap();
+    assert_eq!(
+      missing
+        .iter()
+        .map(|song| song.id.as_str())
+        .collect::<Vec<_>>(),
+      ["gone"]
+    );
+    assert!(index
+      .missing_songs(&[root.join("elsewhere")])
+      .unwrap()
+      .is_empty());
+
+    fs::remove_dir_all(&root).ok();
+  }
+
+  #[test]
+  fn claims_moves_by_title_album_and_artists() {
+    let root = dunce::canonicalize(env::temp_dir()).unwrap();
+    let gone = root.join("moosync-moved-test").join("gone.flac");
+    let connection = sqlite3::open(":memory:").unwrap();
+    connection.execute(WRITER_TABLES).unwrap();
+    let mut insert = connection
+      .prepare(
+        "INSERT INTO allsongs (_id, path, size, title, duration) VALUES ('gone', ?, 10, 'Intro', 60)",
+      )
+      .unwrap();
+    insert.bind(1, gone.to_string_lossy().as_ref()).unwrap();
+    insert.next().unwrap();
+    drop(insert);
+    connection
+      .execute(
+        "INSERT INTO albums (album_id, album_name) VALUES ('album', 'Album');
+        INSERT INTO album_bridge (song, album) VALUES ('gone', 'album');
+        INSERT INTO artists (artist_id, artist_name) VALUES ('a', 'A'), ('b', 'B');
+        INSERT INTO artists_bridge (song, artist) VALUES ('gone', 'a'), ('gone', 'b');",
+      )
+      .unwrap();
+
+    let index = SongIndex::new(connection, &DatabaseSchema::default()).unwrap();
+    let missing = MissingSongs::default();
+    missing.set(index.missing_songs(&[root]).unwrap());
+
+    let song = |album: &str, artists: &[&str]| Song {
+      title: Some("intro".to_string()),
+      duration: Some(60.5),
+      album: Some(Album {
+        album_name: album.to_string(),
+        ..Default::default()
+      }),
+      artists: artists.iter().map(|name| artist(name)).collect(),
+      ..Default::default()
+    };
+    assert!(missing.claim_by_tags(&song("Other", &["A", "B"])).is_none());
+    assert!(missing.claim_by_tags(&song("Album", &["A"])).is_none());
+    assert!(missing.claim_by_tags(&song("", &[])).is_none());
+    assert_eq!(
+      missing
+        .claim_by_tags(&song("album", &["b", "A"]))
+        .map(|song| song.id),
+      Some("gone".to_string())
+    );
+  }
+
+  #[test]
+  fn updates_songs_with_the_same_path() {
+    let mut writer = writer();
+    writer
+      .song(&local_song("first", "/music/a.flac", "Old"))
+      .unwrap();
+    writer.commit().unwrap();
+
+    let id = writer
+      .song(&local_song("second", "/music/a.flac", "New"))
+      .unwrap();
+    assert_eq!(id, "first");
+    assert_eq!(writer.commit().unwrap().songs, vec!["first"]);
+    assert_eq!(
+      strings(&writer, "SELECT _id || ':' || title FROM allsongs"),
+      vec!["first:New"]
+    );
+  }
+
+  #[test]
+  fn links_songs_and_counts_them() {
+    let path = env::temp_dir().join("moosync-writer-links.db");
+    fs::remove_file(&path).ok();
+
+    let mut writer = DatabaseWriter::open(path.clone()).unwrap();
+    writer
+      .playlist(&Playlist {
+        id: "scan-playlist".to_string(),
+        title: "Mix".to_string(),
+        path: "/music/mix.m3u".to_string(),
+      })
+      .unwrap();
+
+    for (i, artists) in [vec!["A", "B"], vec!["A"]].into_iter().enumerate() {
+      writer
+        .song(&Song {
+          album: Some(Album {
+            album_id: "album".to_string(),
+            album_name: "Album".to_string(),
+            ..Default::default()
+          }),
+          artists: artists.into_iter().map(artist).collect(),
+          genre: Some(vec!["Rock".to_string()]),
+          playlist_id: Some("scan-playlist".to_string()),
+          ..local_song(
+            &format!("song-{}", i),
+            &format!("/music/{}.flac", i),
+            "Song",
+          )
+        })
+        .unwrap();
+    }
+
+    // Rewriting a song replaces its links, except for playlists
+    writer
+      .song(&Song {
+        artists: vec![artist("B")],
+        playlist_id: Some("scan-playlist".to_string()),
+        ..local_song("song-1", "/music/1.flac", "Song")
+      })
+      .unwrap();
+    writer.finish().unwrap();
+
+    let writer = DatabaseWriter::open(path.clone()).unwrap();
+    assert_eq!(
+      strings(
+        &writer,
+        "SELECT song || ':' || album FROM album_bridge ORDER BY song"
+      ),
+      vec!["song-0:album"]
+    );
+    assert_eq!(
+      strings(
+        &writer,
+        "SELECT artist_name || ':' || artist_song_count FROM artists ORDER BY artist_name"
+      ),
+      vec!["A:1", "B:2"]
+    );
+    assert_eq!(
+      strings(
+        &writer,
+        "SELECT genre_name || ':' || genre_song_count FROM genres"
+      ),
+      vec!["Rock:1"]
+    );
+    assert_eq!(
+      strings(
+        &writer,
+        "SELECT album_name || ':' || album_song_count FROM albums"
+      ),
+      vec!["Album:1"]
+    );
+    assert_eq!(
+      strings(
+        &writer,
+        "SELECT playlist_name || ':' || playlist_song_count FROM playlists"
+      ),
+      vec!["Mix:2"]
+    );
+
+    drop(writer);
+    fs::remove_file(&path).ok();
+  }
+
+  #[test]
+  fn keeps_no_part_of_a_failed_song() {
+    let mut writer = writer();
+    writer
+      .connection
+      .execute(
+        "DROP TABLE artists;
+        CREATE TABLE artists (
+          artist_id TEXT PRIMARY KEY, artist_name TEXT CHECK (artist_name != 'Bad'),
+          artist_song_count INTEGER DEFAULT 0
+        );",
+      )
+      .unwrap();
+
+    writer
+      .song(&local_song("good", "/music/good.flac", "Good"))
+      .unwrap();
+    let bad = Song {
+      artists: vec![artist("Fine"), artist("Bad")],
+      ..local_song("bad", "/music/bad.flac", "Bad")
+    };
+    assert!(writer.song(&bad).is_err());
+    writer
+      .song(&local_song("later", "/music/later.flac", "Later"))
+      .unwrap();
+
+    assert_eq!(writer.commit().unwrap().songs, vec!["good", "later"]);
+    assert_eq!(
+      strings(&writer, "SELECT _id FROM allsongs ORDER BY _id"),
+      vec!["good", "later"]
+    );
+    assert!(strings(&writer, "SELECT artist_name FROM artists").is_empty());
+    assert!(strings(&writer, "SELECT artist FROM artists_bridge").is_empty());
+  }
+
+  #[test]
+  fn recovers_from_a_failed_commit() {
+    let mut writer = writer();
+    // Songs titled "Dangling" break a deferred foreign key, which fails COMMIT
+    writer
+      .connection
+      .execute(
+        "PRAGMA foreign_keys = ON;
+        CREATE TABLE holds (song TEXT REFERENCES allsongs (_id) DEFERRABLE INITIALLY DEFERRED);
+        CREATE TRIGGER dangling AFTER INSERT ON allsongs WHEN NEW.title = 'Dangling'
+        BEGIN INSERT INTO holds VALUES ('nowhere'); END;",
+      )
+      .unwrap();
+
+    writer
+      .song(&local_song("lost", "/music/lost.flac", "Dangling"))
+      .unwrap();
+    assert!(writer.commit().is_err());
+
+    writer
+      .song(&local_song("kept", "/music/kept.flac", "Kept"))
+      .unwrap();
+    assert_eq!(writer.commit().unwrap().songs, vec!["kept"]);
+    assert_eq!(strings(&writer, "SELECT _id FROM allsongs"), vec!["kept"]);
+  }
+
+  const ROWS: usize = 500_000;
+  const CHUNK: usize = 1000;
+
+  fn song_path(i: usize) -> PathBuf {
+    PathBuf::from(format!(
+      "/music/artist_{}/album_{}/track_{}.flac",
+      i % 997,
+      i % 89,
+      i
+    ))
+  }
+
+  fn create_database(path: &Path) -> Connection {
+    fs::remove_file(path).ok();
+
+    let connection = sqlite3::open(path).unwrap();
+    connection
+      .execute(
+        "CREATE TABLE allsongs (_id TEXT PRIMARY KEY, path TEXT, size INTEGER, mtime INTEGER, hash TEXT);
+        BEGIN;",
+      )
+      .unwrap();
+
+    {
+      let mut insert = connection
+        .prepare("INSERT INTO allsongs (_id, path, size, mtime) VALUES (?, ?, ?, ?)")
+        .unwrap();
+      for i in 0..ROWS {
+        insert.reset().unwrap();
+        insert.bind(1, format!("id-{}", i).as_str()).unwrap();
+        insert
+          .bind(2, song_path(i).to_string_lossy().as_ref())
+          .unwrap();
+        insert.bind(3, (i * 31 % 10_000_000) as i64).unwrap();
+        insert.bind(4, 1_700_000_000_000 + i as i64).unwrap();
+        insert.next().unwrap();
+      }
+    }
+
+    connection.execute("COMMIT").unwrap();
+    connection
+  }
+
+  /// Diffs 500k discovered files against a synthetic 500k-row `allsongs`
+  /// table. Run with
+  /// `cargo test --release diff_500k_files -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn diff_500k_files() {
+    let path = env::temp_dir().join("moosync-bench-allsongs.db");
+
+    let start = Instant::now();
+    let connection = create_database(&path);
+    println!("populated {} rows in {:?}", ROWS, start.elapsed());
+
+    // 90% unchanged, 5% with a new size, 5% not in the table yet
+    let files: Vec<FileStat> = (0..ROWS)
+      .map(|i| match i % 20 {
+        0 => FileStat {
+          path: song_path(ROWS + i),
+          size: 1,
+          mtime: Some(0),
+        },
+        1 => FileStat {
+          path: song_path(i),
+          size: (i * 31 % 10_000_000) as u64 + 1,
+          mtime: Some(1_700_000_000_000 + i as u64),
+        },
+        _ => FileStat {
+          path: song_path(i),
+          size: (i * 31 % 10_000_000) as u64,
+          mtime: Some(1_700_000_000_000 + i as u64),
+        },
+      })
+      .collect();
+
+    let start = Instant::now();
+    let index = SongIndex::new(connection, &DatabaseSchema::default()).unwrap();
+    println!("indexed allsongs in {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let mut changed = 0;
+    for chunk in files.chunks(CHUNK) {
+      changed += index.diff(chunk.to_vec(), false).unwrap().len();
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(changed, ROWS / 10);
+    println!(
+      "diffed {} files in {:?} ({:?} per chunk of {})",
+      files.len(),
+      elapsed,
+      elapsed / (ROWS / CHUNK) as u32,
+      CHUNK
+    );
+
+    drop(index);
+    fs::remove_file(&path).ok();
+  }
 }
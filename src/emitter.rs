@@ -0,0 +1,717 @@
+use std::{
+  collections::VecDeque,
+  mem,
+  sync::{Arc, Condvar, Mutex},
+  time::{Duration, Instant},
+};
+
+use napi::{
+  bindgen_prelude::ToNapiValue,
+  threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+  },
+  Env, JsDeferred, JsFunction,
+};
+
+use crate::{
+  database::{DatabaseWriter, Written},
+  error::ScanError,
+  structs::{
+    MovedSong, Playlist, RemovedSong, ScanEvent, ScanIteratorResult, ScanOptions, ScanProgress,
+    ScanSummary, ScanWarning, ScannedAlbum, ScannedArtist, SkippedPath, SongWithLen, WrittenBatch,
+  },
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScanPhase {
+  Songs,
+  Playlists,
+}
+
+impl ScanPhase {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ScanPhase::Songs => "songs",
+      ScanPhase::Playlists => "playlists",
+    }
+  }
+}
+
+/// Receives everything a scan produces, in order. Each public scan function
+/// plugs its own emitter into the same scan driver.
+pub trait ScanEmitter: Send {
+  fn song(&mut self, song: Result<SongWithLen, ScanError>);
+
+  /// Receives batches when batching is enabled. Emitters without a batch
+  /// target fall back to one `song` call per entry.
+  fn songs(&mut self, songs: Vec<SongWithLen>) {
+    for song in songs {
+      self.song(Ok(song));
+    }
+  }
+
+  fn playlist(&mut self, playlist: Result<Playlist, ScanError>);
+
+  /// A database row whose file was found under a new path. Called right
+  /// before the song itself is handed over.
+  fn moved(&mut self, _moved: MovedSong) {}
+
+  /// Database rows whose file no longer exists
+  fn removed(&mut self, _songs: Vec<RemovedSong>) {}
+
+  fn progress(&mut self, _progress: ScanProgress) {}
+
+  fn warning(&mut self, _warning: ScanWarning) {}
+
+  fn skipped(&mut self, _skipped: SkippedPath) {}
+
+  /// Ids of rows the scanner wrote to the database itself
+  fn written(&mut self, _written: WrittenBatch) {}
+
+  fn albums(&mut self, _albums: Vec<ScannedAlbum>) {}
+
+  fn artists(&mut self, _artists: Vec<ScannedArtist>) {}
+
+  /// Called before the resume journal commits. Everything emitted so far
+  /// has to be handed over or stored for good by the time this returns.
+  /// Returns false if some of it was dropped instead, so the journal must
+  /// not commit.
+  fn checkpoint(&mut self) -> bool {
+    true
+  }
+
+  /// Called when the scan could not continue past the given phase.
+  /// `end` is not called afterwards.
+  fn failed(&mut self, phase: ScanPhase, err: ScanError);
+
+  fn end(&mut self, summary: ScanSummary);
+}
+
+pub type Deferred<T> = JsDeferred<T, Box<dyn FnOnce(Env) -> napi::Result<T> + Send>>;
+
+fn resolve<T: ToNapiValue + Send + 'static>(deferred: Deferred<T>, value: T) {
+  deferred.resolve(Box::new(move |_| Ok(value)))
+}
+
+pub enum Completion {
+  Callback(ThreadsafeFunction<ScanSummary, ErrorStrategy::CalleeHandled>),
+  Promise(Option<Deferred<ScanSummary>>),
+}
+
+/// Counts callbacks which JS has picked up, so completion can wait for every
+/// song and playlist to be handed over first. Separate threadsafe functions
+/// don't share a queue, so nothing else orders them.
+#[derive(Default, Clone)]
+struct Delivered(Arc<(Mutex<usize>, Condvar)>);
+
+impl Delivered {
+  fn bump(&self) {
+    let (lock, cvar) = &*self.0;
+    *lock.lock().unwrap() += 1;
+    cvar.notify_all();
+  }
+
+  fn wait_for(&self, count: usize) {
+    let (lock, cvar) = &*self.0;
+    let mut delivered = lock.lock().unwrap();
+    while *delivered < count {
+      delivered = cvar.wait(delivered).unwrap();
+    }
+  }
+}
+
+type Callback<T> = ThreadsafeFunction<napi::Result<T>, ErrorStrategy::CalleeHandled>;
+
+fn create_callback<T: ToNapiValue + 'static>(
+  callback: JsFunction,
+  delivered: &Delivered,
+) -> napi::Result<Callback<T>> {
+  let delivered = delivered.clone();
+  callback.create_threadsafe_function(0, move |ctx: ThreadSafeCallContext<napi::Result<T>>| {
+    delivered.bump();
+    ctx.value.map(|v| vec![v])
+  })
+}
+
+/// Emitter backing `scanFiles` and `scanFilesAsync`
+pub struct CallbackEmitter {
+  songs: Callback<SongWithLen>,
+  song_batches: Option<Callback<Vec<SongWithLen>>>,
+  playlists: Callback<Playlist>,
+  written: Option<Callback<WrittenBatch>>,
+  albums: Option<Callback<Vec<ScannedAlbum>>>,
+  artists: Option<Callback<Vec<ScannedArtist>>>,
+  moved: Option<Callback<MovedSong>>,
+  removed: Option<Callback<Vec<RemovedSong>>>,
+  completion: Completion,
+  delivered: Delivered,
+  sent: usize,
+}
+
+impl CallbackEmitter {
+  pub fn new(
+    songs: JsFunction,
+    playlists: JsFunction,
+    completion: Completion,
+    options: &mut ScanOptions,
+  ) -> napi::Result<Self> {
+    let delivered = Delivered::default();
+    Ok(Self {
+      songs: create_callback(songs, &delivered)?,
+      song_batches: options
+        .on_song_batch
+        .take()
+        .map(|callback| create_callback(callback, &delivered))
+        .transpose()?,
+      playlists: create_callback(playlists, &delivered)?,
+      written: options
+        .on_written
+        .take()
+        .map(|callback| create_callback(callback, &delivered))
+        .transpose()?,
+      albums: options
+        .on_albums
+        .take()
+        .map(|callback| create_callback(callback, &delivered))
+        .transpose()?,
+      artists: options
+        .on_artists
+        .take()
+        .map(|callback| create_callback(callback, &delivered))
+        .transpose()?,
+      moved: options
+        .on_moved
+        .take()
+        .map(|callback| create_callback(callback, &delivered))
+        .transpose()?,
+      removed: options
+        .on_removed
+        .take()
+        .map(|callback| create_callback(callback, &delivered))
+        .transpose()?,
+      completion,
+      delivered,
+      sent: 0,
+    })
+  }
+}
+
+impl ScanEmitter for CallbackEmitter {
+  fn song(&mut self, song: Result<SongWithLen, ScanError>) {
+    self.sent += 1;
+    self.songs.call(
+      Ok(song.map_err(|e| e.into())),
+      ThreadsafeFunctionCallMode::Blocking,
+    );
+  }
+
+  fn songs(&mut self, songs: Vec<SongWithLen>) {
+    match &self.song_batches {
+      Some(tsfn) => {
+        self.sent += 1;
+        tsfn.call(Ok(Ok(songs)), ThreadsafeFunctionCallMode::Blocking);
+      }
+      None => {
+        for song in songs {
+          self.song(Ok(song));
+        }
+      }
+    }
+  }
+
+  fn playlist(&mut self, playlist: Result<Playlist, ScanError>) {
+    self.sent += 1;
+    self.playlists.call(
+      Ok(playlist.map_err(|e| e.into())),
+      ThreadsafeFunctionCallMode::Blocking,
+    );
+  }
+
+  fn written(&mut self, written: WrittenBatch) {
+    if let Some(tsfn) = &self.written {
+      self.sent += 1;
+      tsfn.call(Ok(Ok(written)), ThreadsafeFunctionCallMode::Blocking);
+    }
+  }
+
+  fn albums(&mut self, albums: Vec<ScannedAlbum>) {
+    if let Some(tsfn) = &self.albums {
+      self.sent += 1;
+      tsfn.call(Ok(Ok(albums)), ThreadsafeFunctionCallMode::Blocking);
+    }
+  }
+
+  fn artists(&mut self, artists: Vec<ScannedArtist>) {
+    if let Some(tsfn) = &self.artists {
+      self.sent += 1;
+      tsfn.call(Ok(Ok(artists)), ThreadsafeFunctionCallMode::Blocking);
+    }
+  }
+
+  fn moved(&mut self, moved: MovedSong) {
+    if let Some(tsfn) = &self.moved {
+      self.sent += 1;
+      tsfn.call(Ok(Ok(moved)), ThreadsafeFunctionCallMode::Blocking);
+    }
+  }
+
+  fn removed(&mut self, songs: Vec<RemovedSong>) {
+    if let Some(tsfn) = &self.removed {
+      self.sent += 1;
+      tsfn.call(Ok(Ok(songs)), ThreadsafeFunctionCallMode::Blocking);
+    }
+  }
+
+  /// Calls are only queued, so wait until JS has actually run every one
+  fn checkpoint(&mut self) -> bool {
+    self.delivered.wait_for(self.sent);
+    true
+  }
+
+  fn failed(&mut self, phase: ScanPhase, err: ScanError) {
+    match &mut self.completion {
+      Completion::Promise(deferred) => {
+        if let Some(deferred) = deferred.take() {
+          self.delivered.wait_for(self.sent);
+          deferred.reject(err.into());
+        }
+      }
+      Completion::Callback(_) => match phase {
+        ScanPhase::Songs => self.song(Err(err)),
+        ScanPhase::Playlists => self.playlist(Err(err)),
+      },
+    }
+  }
+
+  fn end(&mut self, summary: ScanSummary) {
+    self.delivered.wait_for(self.sent);
+    match &mut self.completion {
+      Completion::Callback(tsfn) => {
+        tsfn.call(Ok(summary), ThreadsafeFunctionCallMode::Blocking);
+      }
+      Completion::Promise(deferred) => {
+        if let Some(deferred) = deferred.take() {
+          resolve(deferred, summary);
+        }
+      }
+    }
+  }
+}
+
+/// Writes songs and playlists to the database instead of handing them over.
+/// The wrapped emitter gets the ids of each committed batch, and everything
+/// else as usual.
+pub struct WritingEmitter<'a, E: ScanEmitter> {
+  writer: Option<DatabaseWriter>,
+  inner: &'a mut E,
+  progress: ScanProgress,
+}
+
+impl<'a, E: ScanEmitter> WritingEmitter<'a, E> {
+  pub fn new(writer: DatabaseWriter, inner: &'a mut E) -> Self {
+    Self {
+      writer: Some(writer),
+      inner,
+      progress: ScanProgress::default(),
+    }
+  }
+
+  /// Returns false if the write failed
+  fn report(&mut self, written: Result<Written, ScanError>) -> bool {
+    match written {
+      Ok(written) => {
+        if !written.songs.is_empty() || !written.playlists.is_empty() {
+          self.inner.written(WrittenBatch {
+            songs: written.songs,
+            playlists: written.playlists,
+            progress: self.progress,
+          });
+        }
+        true
+      }
+      Err(e) => {
+        self.inner.song(Err(e));
+        false
+      }
+    }
+  }
+
+  fn commit(&mut self) -> bool {
+    match self.writer.as_mut() {
+      Some(writer) => {
+        let written = writer.commit();
+        self.report(written)
+      }
+      None => true,
+    }
+  }
+}
+
+impl<E: ScanEmitter> ScanEmitter for WritingEmitter<'_, E> {
+  fn song(&mut self, song: Result<SongWithLen, ScanError>) {
+    let (Ok(song), Some(writer)) = (song.as_ref(), self.writer.as_mut()) else {
+      return self.inner.song(song);
+    };
+
+    self.progress = ScanProgress {
+      current: song.current,
+      total: song.size,
+    };
+
+    match writer.song(&song.song) {
+      Ok(_) if writer.due() => {
+        self.commit();
+      }
+      Ok(_) => {}
+      Err(e) => self.inner.song(Err(e)),
+    }
+  }
+
+  fn playlist(&mut self, playlist: Result<Playlist, ScanError>) {
+    let (Ok(entry), Some(writer)) = (playlist.as_ref(), self.writer.as_mut()) else {
+      return self.inner.playlist(playlist);
+    };
+
+    if let Err(e) = writer.playlist(entry) {
+      self.inner.playlist(Err(e));
+    }
+  }
+
+  fn albums(&mut self, albums: Vec<ScannedAlbum>) {
+    self.inner.albums(albums);
+  }
+
+  fn artists(&mut self, artists: Vec<ScannedArtist>) {
+    self.inner.artists(artists);
+  }
+
+  fn moved(&mut self, moved: MovedSong) {
+    self.inner.moved(moved);
+  }
+
+  fn removed(&mut self, songs: Vec<RemovedSong>) {
+    if let Some(writer) = self.writer.as_mut() {
+      if let Err(e) = writer.remove(&songs) {
+        self.inner.song(Err(e));
+      }
+    }
+    self.inner.removed(songs);
+  }
+
+  fn progress(&mut self, progress: ScanProgress) {
+    self.progress = progress;
+    self.inner.progress(progress);
+  }
+
+  fn warning(&mut self, warning: ScanWarning) {
+    self.inner.warning(warning);
+  }
+
+  fn skipped(&mut self, skipped: SkippedPath) {
+    self.inner.skipped(skipped);
+  }
+
+  fn checkpoint(&mut self) -> bool {
+    // Keep the journal from recording a batch the database never got
+    let committed = self.commit();
+    self.inner.checkpoint() && committed
+  }
+
+  fn failed(&mut self, phase: ScanPhase, err: ScanError) {
+    self.commit();
+    self.inner.failed(phase, err);
+  }
+
+  fn end(&mut self, summary: ScanSummary) {
+    if let Some(writer) = self.writer.take() {
+      let written = writer.finish();
+      self.report(written);
+    }
+    self.inner.end(summary);
+  }
+}
+
+/// Collects songs until the batch is full or its time window runs out
+pub struct SongBatch {
+  size: usize,
+  window: Option<Duration>,
+  songs: Vec<SongWithLen>,
+  opened: Instant,
+}
+
+impl SongBatch {
+  pub fn new(size: usize, window: Option<Duration>) -> Self {
+    Self {
+      size,
+      window,
+      songs: Vec::with_capacity(size.min(1024)),
+      opened: Instant::now(),
+    }
+  }
+
+  /// Time until the current batch has to be flushed, if anything is pending
+  pub fn time_left(&self) -> Option<Duration> {
+    if self.songs.is_empty() {
+      return None;
+    }
+
+    self
+      .window
+      .map(|window| window.saturating_sub(self.opened.elapsed()))
+  }
+
+  pub fn push(&mut self, song: SongWithLen, emitter: &mut impl ScanEmitter) {
+    if self.songs.is_empty() {
+      self.opened = Instant::now();
+    }
+
+    self.songs.push(song);
+    if self.songs.len() >= self.size || self.time_left() == Some(Duration::ZERO) {
+      self.flush(emitter);
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.songs.is_empty()
+  }
+
+  pub fn flush(&mut self, emitter: &mut impl ScanEmitter) {
+    if !self.songs.is_empty() {
+      emitter.songs(mem::take(&mut self.songs));
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct StreamState {
+  events: VecDeque<ScanEvent>,
+  waiting: VecDeque<Deferred<ScanIteratorResult>>,
+  done: bool,
+  /// Set once unconsumed events were thrown away
+  discarded: bool,
+  /// Signalled whenever JS takes events out of the buffer
+  pulled: Arc<Condvar>,
+}
+
+impl StreamState {
+  /// Hands the next buffered event to `deferred`, or parks it until the scan
+  /// produces one.
+  pub fn next(&mut self, deferred: Deferred<ScanIteratorResult>) {
+    if let Some(event) = self.events.pop_front() {
+      resolve(
+        deferred,
+        ScanIteratorResult {
+          value: Some(event),
+          done: false,
+        },
+      );
+      self.pulled.notify_all();
+    } else if self.done {
+      resolve(
+        deferred,
+        ScanIteratorResult {
+          value: None,
+          done: true,
+        },
+      );
+    } else {
+      self.waiting.push_back(deferred);
+    }
+  }
+
+  fn push(&mut self, event: ScanEvent) {
+    if self.done {
+      return;
+    }
+
+    if let Some(deferred) = self.waiting.pop_front() {
+      resolve(
+        deferred,
+        ScanIteratorResult {
+          value: Some(event),
+          done: false,
+        },
+      );
+    } else {
+      self.events.push_back(event);
+    }
+  }
+
+  /// Ends the stream. Buffered events are still handed out before `done`.
+  pub fn close(&mut self) {
+    self.done = true;
+    if self.events.is_empty() {
+      while let Some(deferred) = self.waiting.pop_front() {
+        resolve(
+          deferred,
+          ScanIteratorResult {
+            value: None,
+            done: true,
+          },
+        );
+      }
+    }
+  }
+
+  /// Ends the stream and throws away anything not yet consumed.
+  pub fn discard(&mut self) {
+    self.discarded |= !self.events.is_empty();
+    self.events.clear();
+    self.pulled.notify_all();
+    self.close();
+  }
+}
+
+/// Emitter backing `scanFilesStream`
+pub struct StreamEmitter {
+  state: Arc<Mutex<StreamState>>,
+}
+
+impl StreamEmitter {
+  pub fn new(state: Arc<Mutex<StreamState>>) -> Self {
+    Self { state }
+  }
+
+  fn push(&self, event: ScanEvent) {
+    self.state.lock().unwrap().push(event);
+  }
+
+  fn error(&self, phase: ScanPhase, err: ScanError) {
+    self.push(ScanEvent {
+      event_type: "error".to_string(),
+      phase: Some(phase.as_str().to_string()),
+      message: Some(err.to_string()),
+      ..Default::default()
+    });
+  }
+}
+
+impl ScanEmitter for StreamEmitter {
+  fn song(&mut self, song: Result<SongWithLen, ScanError>) {
+    match song {
+      Ok(song) => self.push(ScanEvent {
+        event_type: "song".to_string(),
+        progress: Some(ScanProgress {
+          current: song.current,
+          total: song.size,
+        }),
+        song: Some(song.song),
+        ..Default::default()
+      }),
+      Err(e) => self.error(ScanPhase::Songs, e),
+    }
+  }
+
+  fn songs(&mut self, songs: Vec<SongWithLen>) {
+    self.push(ScanEvent {
+      event_type: "songs".to_string(),
+      progress: songs.last().map(|song| ScanProgress {
+        current: song.current,
+        total: song.size,
+      }),
+      songs: Some(songs),
+      ..Default::default()
+    });
+  }
+
+  fn playlist(&mut self, playlist: Result<Playlist, ScanError>) {
+    match playlist {
+      Ok(playlist) => self.push(ScanEvent {
+        event_type: "playlist".to_string(),
+        playlist: Some(playlist),
+        ..Default::default()
+      }),
+      Err(e) => self.error(ScanPhase::Playlists, e),
+    }
+  }
+
+  fn written(&mut self, written: WrittenBatch) {
+    self.push(ScanEvent {
+      event_type: "written".to_string(),
+      progress: Some(written.progress),
+      written: Some(written),
+      ..Default::default()
+    });
+  }
+
+  fn albums(&mut self, albums: Vec<ScannedAlbum>) {
+    self.push(ScanEvent {
+      event_type: "albums".to_string(),
+      albums: Some(albums),
+      ..Default::default()
+    });
+  }
+
+  fn artists(&mut self, artists: Vec<ScannedArtist>) {
+    self.push(ScanEvent {
+      event_type: "artists".to_string(),
+      artists: Some(artists),
+      ..Default::default()
+    });
+  }
+
+  fn moved(&mut self, moved: MovedSong) {
+    self.push(ScanEvent {
+      event_type: "moved".to_string(),
+      moved: Some(moved),
+      ..Default::default()
+    });
+  }
+
+  fn removed(&mut self, songs: Vec<RemovedSong>) {
+    self.push(ScanEvent {
+      event_type: "removed".to_string(),
+      removed: Some(songs),
+      ..Default::default()
+    });
+  }
+
+  fn progress(&mut self, progress: ScanProgress) {
+    self.push(ScanEvent {
+      event_type: "progress".to_string(),
+      progress: Some(progress),
+      ..Default::default()
+    });
+  }
+
+  fn warning(&mut self, warning: ScanWarning) {
+    self.push(ScanEvent {
+      event_type: "warning".to_string(),
+      warning: Some(warning),
+      ..Default::default()
+    });
+  }
+
+  fn skipped(&mut self, skipped: SkippedPath) {
+    self.push(ScanEvent {
+      event_type: "skipped".to_string(),
+      skipped: Some(skipped),
+      ..Default::default()
+    });
+  }
+
+  /// Blocks until JS has pulled every buffered event, or dropped them by
+  /// leaving its `for await` loop
+  fn checkpoint(&mut self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    let pulled = state.pulled.clone();
+    while !state.events.is_empty() {
+      state = pulled.wait(state).unwrap();
+    }
+    !state.discarded
+  }
+
+  fn failed(&mut self, phase: ScanPhase, err: ScanError) {
+    self.error(phase, err);
+    self.state.lock().unwrap().close();
+  }
+
+  fn end(&mut self, summary: ScanSummary) {
+    self.push(ScanEvent {
+      event_type: "end".to_string(),
+      summary: Some(summary),
+      ..Default::default()
+    });
+    self.state.lock().unwrap().close();
+  }
+}
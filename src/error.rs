@@ -20,13 +20,15 @@ pub enum ScanError {
   Sqlite3Error(sqlite3::Error),
   ParseFloatError(ParseFloatError),
   JWalkError(jwalk::Error),
+  RegexError(regex::Error),
+  DatabaseSchema(String),
 }
 
 impl fmt::Display for ScanError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
       ScanError::IOError(e) => write!(f, "{:?}", e.to_string()),
-      ScanError::SystemTimeError(e) => write!(f, "{}", e.to_string()),
+      ScanError::SystemTimeError(e) => write!(f, "{}", e),
       ScanError::String(e) => write!(f, "{:?}", e),
       ScanError::ImageBufferError(e) => write!(f, "{:?}", e),
       ScanError::ImageError(e) => write!(f, "{:?}", e),
@@ -35,6 +37,8 @@ impl fmt::Display for ScanError {
       ScanError::Sqlite3Error(e) => write!(f, "{:?}", e),
       ScanError::ParseFloatError(e) => write!(f, "{:?}", e),
       ScanError::JWalkError(e) => write!(f, "{:?}", e),
+      ScanError::RegexError(e) => write!(f, "{}", e),
+      ScanError::DatabaseSchema(e) => write!(f, "Invalid database schema: {}", e),
     }
   }
 }
@@ -53,7 +57,7 @@ impl From<SystemTimeError> for ScanError {
 
 impl From<&str> for ScanError {
   fn from(value: &str) -> Self {
-    ScanError::String(format!("{}", value))
+    ScanError::String(value.to_string())
   }
 }
 
@@ -99,8 +103,14 @@ impl From<jwalk::Error> for ScanError {
   }
 }
 
-impl Into<napi::Error> for ScanError {
-  fn into(self) -> napi::Error {
-    napi::Error::new(napi::Status::Unknown, self.to_string())
+impl From<regex::Error> for ScanError {
+  fn from(value: regex::Error) -> Self {
+    ScanError::RegexError(value)
+  }
+}
+
+impl From<ScanError> for napi::Error {
+  fn from(value: ScanError) -> Self {
+    napi::Error::new(napi::Status::Unknown, value.to_string())
   }
 }
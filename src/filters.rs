@@ -0,0 +1,387 @@
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use regex::Regex;
+
+use crate::error::ScanError;
+
+/// Translates a glob into an anchored regex.
+///
+/// `*` and `?` stay within one path component, `**` crosses components and
+/// `**/` also matches zero directories.
+pub fn glob_to_regex(glob: &str) -> String {
+  let mut regex = String::from("^");
+  let mut chars = glob.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '*' => {
+        if chars.peek() == Some(&'*') {
+          chars.next();
+          if chars.peek() == Some(&'/') {
+            chars.next();
+            regex.push_str("(?:.*/)?");
+          } else {
+            regex.push_str(".*");
+          }
+        } else {
+          regex.push_str("[^/]*");
+        }
+      }
+      '?' => regex.push_str("[^/]"),
+      '[' => {
+        let mut class = String::new();
+        let mut closed = false;
+        for c in chars.by_ref() {
+          if c == ']' {
+            closed = true;
+            break;
+          }
+          class.push(c);
+        }
+
+        if closed && !class.is_empty() {
+          regex.push('[');
+          if let Some(negated) = class.strip_prefix('!') {
+            regex.push('^');
+            regex.push_str(&negated.replace('\\', "\\\\"));
+          } else {
+            regex.push_str(&class.replace('\\', "\\\\"));
+          }
+          regex.push(']');
+        } else {
+          regex.push_str(&regex::escape(&format!("[{}", class)));
+        }
+      }
+      c => regex.push_str(&regex::escape(&c.to_string())),
+    }
+  }
+
+  regex.push('$');
+  regex
+}
+
+/// Converts a path into the `/` separated form globs are matched against
+pub fn normalize_path(path: &Path) -> String {
+  path.to_string_lossy().replace('\\', "/")
+}
+
+#[derive(Debug)]
+struct GlobPattern {
+  regex: Regex,
+  // Patterns without a `/` only look at the file or folder name
+  basename: bool,
+}
+
+/// Exclusion rules passed in through `ScanOptions.exclude`
+#[derive(Debug, Default)]
+pub struct PathFilter {
+  patterns: Vec<GlobPattern>,
+}
+
+impl PathFilter {
+  pub fn new(globs: &[String]) -> Result<Self, ScanError> {
+    let mut patterns = vec![];
+    for glob in globs {
+      let glob = glob.trim().replace('\\', "/");
+      if glob.is_empty() {
+        continue;
+      }
+
+      patterns.push(GlobPattern {
+        regex: Regex::new(&glob_to_regex(glob.trim_start_matches('/')))?,
+        basename: !glob.contains('/'),
+      });
+    }
+
+    Ok(Self { patterns })
+  }
+
+  /// Checks `path` against the rules. Paths are matched relative to the root
+  /// they were found under, folders with a trailing `/`.
+  pub fn is_excluded(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+    if self.patterns.is_empty() {
+      return false;
+    }
+
+    let mut relative = normalize_path(path.strip_prefix(root).unwrap_or(path));
+    let name = path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+
+    if is_dir {
+      relative.push('/');
+    }
+
+    self.patterns.iter().any(|pattern| {
+      if pattern.basename {
+        pattern.regex.is_match(&name)
+      } else {
+        pattern.regex.is_match(&relative)
+      }
+    })
+  }
+}
+
+#[derive(Debug)]
+struct IgnoreRule {
+  regex: Regex,
+  negated: bool,
+  dir_only: bool,
+  // Patterns with a `/` before their end are matched relative to the ignore
+  // file, others against the name at any level below it
+  anchored: bool,
+}
+
+/// Rules read from a `.moosyncignore` file, which uses gitignore syntax
+#[derive(Debug)]
+pub struct IgnoreFile {
+  pub path: PathBuf,
+  base: PathBuf,
+  rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+  pub fn parse(path: PathBuf, contents: &str) -> Result<Self, ScanError> {
+    let mut rules = vec![];
+    for line in contents.lines() {
+      let line = line.trim_end_matches('\r');
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      // Trailing spaces are ignored unless escaped
+      let mut pattern = line.trim_end_matches(' ').to_string();
+      if line.len() > pattern.len() && pattern.ends_with('\\') {
+        pattern.pop();
+        pattern.push(' ');
+      }
+
+      // A leading `\` escapes a literal `!` or `#`
+      let negated = pattern.starts_with('!');
+      if negated || pattern.starts_with("\\!") || pattern.starts_with("\\#") {
+        pattern.remove(0);
+      }
+
+      let dir_only = pattern.ends_with('/');
+      let pattern = pattern.trim_end_matches('/');
+      if pattern.is_empty() {
+        continue;
+      }
+
+      rules.push(IgnoreRule {
+        regex: Regex::new(&glob_to_regex(pattern.trim_start_matches('/')))?,
+        negated,
+        dir_only,
+        anchored: pattern.contains('/'),
+      });
+    }
+
+    Ok(Self {
+      base: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+      path,
+      rules,
+    })
+  }
+
+  /// Returns `Some(true)` if the last rule matching `path` ignores it, and
+  /// `Some(false)` if it was re-included with `!`.
+  fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+    let relative = normalize_path(path.strip_prefix(&self.base).ok()?);
+    let name = path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+
+    self
+      .rules
+      .iter()
+      .rev()
+      .find(|rule| {
+        (is_dir || !rule.dir_only)
+          && if rule.anchored {
+            rule.regex.is_match(&relative)
+          } else {
+            rule.regex.is_match(&name)
+          }
+      })
+      .map(|rule| !rule.negated)
+  }
+}
+
+/// Ignore files which apply to a folder, outermost first. Each folder hands a
+/// copy to its subfolders, adding its own file if it has one.
+#[derive(Debug, Default, Clone)]
+pub struct IgnoreStack(Vec<Arc<IgnoreFile>>);
+
+impl IgnoreStack {
+  pub fn push(&mut self, file: IgnoreFile) {
+    self.0.push(Arc::new(file))
+  }
+
+  /// Finds the ignore file whose rules exclude `path`, if any. Deeper files
+  /// take precedence over the ones above them.
+  pub fn ignored_by(&self, path: &Path, is_dir: bool) -> Option<&IgnoreFile> {
+    self.0.iter().rev().find_map(|file| {
+      file
+        .matches(path, is_dir)
+        .map(|ignored| ignored.then_some(file.as_ref()))
+    })?
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filter(globs: &[&str]) -> PathFilter {
+    PathFilter::new(
+      &globs
+        .iter()
+        .map(|glob| glob.to_string())
+        .collect::<Vec<_>>(),
+    )
+    .unwrap()
+  }
+
+  fn excluded(filter: &PathFilter, path: &str, is_dir: bool) -> bool {
+    filter.is_excluded(Path::new("/music"), &Path::new("/music").join(path), is_dir)
+  }
+
+  #[test]
+  fn translates_globs() {
+    assert_eq!(glob_to_regex("*.bak"), r"^[^/]*\.bak$");
+    assert_eq!(glob_to_regex("**/Samples/**"), "^(?:.*/)?Samples/.*$");
+    assert_eq!(glob_to_regex("track?.[!a-c]"), r"^track[^/]\.[^a-c]$");
+    assert_eq!(glob_to_regex("[unclosed"), r"^\[unclosed$");
+  }
+
+  #[test]
+  fn excludes_folders_at_any_depth() {
+    let filter = filter(&["**/Samples/**"]);
+    assert!(excluded(&filter, "Samples", true));
+    assert!(excluded(&filter, "Artist/Album/Samples", true));
+    assert!(excluded(&filter, "Artist/Samples/kick.wav", false));
+    assert!(!excluded(&filter, "Artist/Samples", false));
+    assert!(!excluded(&filter, "Artist/Samples Vol. 2/kick.wav", false));
+  }
+
+  #[test]
+  fn matches_names_without_a_slash() {
+    let filter = filter(&["*.bak"]);
+    assert!(excluded(&filter, "song.bak", false));
+    assert!(excluded(&filter, "Artist/Album/song.bak", false));
+    assert!(!excluded(&filter, "song.bak.flac", false));
+  }
+
+  #[test]
+  fn anchors_patterns_with_a_slash_to_the_root() {
+    let filter = filter(&["/Podcasts/*", "Drafts/*.wav"]);
+    assert!(excluded(&filter, "Podcasts/episode.mp3", false));
+    assert!(!excluded(&filter, "Artist/Podcasts/episode.mp3", false));
+    assert!(!excluded(&filter, "Podcasts/2023/episode.mp3", false));
+    assert!(excluded(&filter, "Drafts/take.wav", false));
+    assert!(!excluded(&filter, "Old/Drafts/take.wav", false));
+  }
+
+  #[test]
+  fn matches_case_sensitively() {
+    let filter = filter(&["*.bak", "**/Samples/**"]);
+    assert!(!excluded(&filter, "SONG.BAK", false));
+    assert!(!excluded(&filter, "samples/kick.wav", false));
+  }
+
+  #[test]
+  fn accepts_backslashes() {
+    let filter = filter(&[r"Drafts\*.wav"]);
+    assert!(excluded(&filter, "Drafts/take.wav", false));
+  }
+
+  fn ignore_stack(files: &[(&str, &str)]) -> IgnoreStack {
+    let mut stack = IgnoreStack::default();
+    for (path, contents) in files {
+      stack.push(IgnoreFile::parse(PathBuf::from(path), contents).unwrap());
+    }
+    stack
+  }
+
+  fn ignored(stack: &IgnoreStack, path: &str, is_dir: bool) -> Option<String> {
+    stack
+      .ignored_by(Path::new(path), is_dir)
+      .map(|file| normalize_path(&file.path))
+  }
+
+  #[test]
+  fn reincludes_negated_names() {
+    let stack = ignore_stack(&[("/music/.moosyncignore", "*.wav\n!keep.wav\n")]);
+    assert!(ignored(&stack, "/music/take.wav", false).is_some());
+    assert!(ignored(&stack, "/music/Album/keep.wav", false).is_none());
+
+    // The last matching rule wins
+    let stack = ignore_stack(&[("/music/.moosyncignore", "!keep.wav\n*.wav\n")]);
+    assert!(ignored(&stack, "/music/keep.wav", false).is_some());
+  }
+
+  #[test]
+  fn lets_deeper_files_override_their_parents() {
+    let stack = ignore_stack(&[
+      ("/music/.moosyncignore", "*.wav\n"),
+      ("/music/Stems/.moosyncignore", "!*.wav\n"),
+      ("/music/Stems/Old/.moosyncignore", "old.wav\n"),
+    ]);
+    assert_eq!(
+      ignored(&stack, "/music/take.wav", false).as_deref(),
+      Some("/music/.moosyncignore")
+    );
+    assert!(ignored(&stack, "/music/Stems/take.wav", false).is_none());
+    assert_eq!(
+      ignored(&stack, "/music/Stems/Old/old.wav", false).as_deref(),
+      Some("/music/Stems/Old/.moosyncignore")
+    );
+  }
+
+  #[test]
+  fn applies_trailing_slash_rules_to_folders_only() {
+    let stack = ignore_stack(&[("/music/.moosyncignore", "Samples/\n")]);
+    assert!(ignored(&stack, "/music/Artist/Samples", true).is_some());
+    assert!(ignored(&stack, "/music/Artist/Samples", false).is_none());
+  }
+
+  #[test]
+  fn anchors_ignore_rules_with_a_slash() {
+    let stack = ignore_stack(&[(
+      "/music/.moosyncignore",
+      "/Podcasts\nDrafts/*.wav\ncover.jpg\n",
+    )]);
+    assert!(ignored(&stack, "/music/Podcasts", true).is_some());
+    assert!(ignored(&stack, "/music/Artist/Podcasts", true).is_none());
+    assert!(ignored(&stack, "/music/Drafts/take.wav", false).is_some());
+    assert!(ignored(&stack, "/music/Artist/Drafts/take.wav", false).is_none());
+    assert!(ignored(&stack, "/music/Artist/Album/cover.jpg", false).is_some());
+  }
+
+  #[test]
+  fn reads_escaped_characters() {
+    let stack = ignore_stack(&[(
+      "/music/.moosyncignore",
+      "# a comment\n\\#1.mp3\n\\!important.mp3\ntrailing\\ \nspaces   \n",
+    )]);
+    assert!(ignored(&stack, "/music/# a comment", false).is_none());
+    assert!(ignored(&stack, "/music/#1.mp3", false).is_some());
+    assert!(ignored(&stack, "/music/!important.mp3", false).is_some());
+    assert!(ignored(&stack, "/music/trailing ", false).is_some());
+    assert!(ignored(&stack, "/music/trailing", false).is_none());
+    assert!(ignored(&stack, "/music/spaces", false).is_some());
+  }
+
+  #[test]
+  fn skips_files_outside_the_ignore_file() {
+    let stack = ignore_stack(&[("/music/Album/.moosyncignore", "/*.wav\n*.bak\n")]);
+    assert!(ignored(&stack, "/music/Album/take.wav", false).is_some());
+    assert!(ignored(&stack, "/music/take.wav", false).is_none());
+    assert!(ignored(&stack, "/other/song.bak", false).is_none());
+  }
+}
@@ -0,0 +1,242 @@
+use std::{
+  collections::HashSet,
+  mem,
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
+};
+
+use sqlite3::{Connection, State};
+
+use crate::{
+  error::ScanError,
+  structs::ScanWarning,
+  utils::{check_directory, open_scratch_database},
+};
+
+const JOURNAL_FILE: &str = "scan_journal.db";
+
+// Recorded paths are committed in groups. A crash loses at most the last
+// group, whose files are then delivered again rather than skipped.
+const CHECKPOINT_SIZE: usize = 500;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Paths handed to JS by a scan which hasn't completed yet, kept in a sqlite
+/// file next to the thumbnails. The next scan of the same folders skips them.
+pub struct ScanJournal {
+  path: PathBuf,
+  connection: Connection,
+  delivered: HashSet<PathBuf>,
+  pending: usize,
+  committed: Instant,
+  warnings: Vec<ScanWarning>,
+}
+
+fn roots_key(roots: &[PathBuf]) -> String {
+  let mut roots: Vec<String> = roots
+    .iter()
+    .map(|root| {
+      dunce::canonicalize(root)
+        .unwrap_or(root.clone())
+        .to_string_lossy()
+        .to_string()
+    })
+    .collect();
+  roots.sort();
+  roots.dedup();
+  roots.join("\n")
+}
+
+fn create_tables(connection: &Connection, roots: &str) -> Result<(), ScanError> {
+  connection.execute(
+    "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS delivered (path TEXT PRIMARY KEY);",
+  )?;
+
+  let mut cursor = connection.prepare("SELECT value FROM meta WHERE key = 'roots'")?;
+  let stored = match cursor.next()? {
+    State::Row => Some(cursor.read::<String>(0)?),
+    State::Done => None,
+  };
+  drop(cursor);
+
+  // A journal left behind by a scan of other folders doesn't apply
+  if stored.as_deref() != Some(roots) {
+    connection.execute("DELETE FROM delivered")?;
+    let mut insert =
+      connection.prepare("INSERT OR REPLACE INTO meta (key, value) VALUES ('roots', ?)")?;
+    insert.bind(1, roots)?;
+    insert.next()?;
+  }
+
+  Ok(())
+}
+
+impl ScanJournal {
+  pub fn open(dir: &Path, roots: &[PathBuf]) -> Result<Self, ScanError> {
+    check_directory(dir.to_path_buf())?;
+
+    let path = dir.join(JOURNAL_FILE);
+    let roots = roots_key(roots);
+    let (connection, discarded) =
+      open_scratch_database(&path, |connection| create_tables(connection, &roots))?;
+
+    let mut delivered = HashSet::new();
+    let mut cursor = connection.prepare("SELECT path FROM delivered")?;
+    while let State::Row = cursor.next()? {
+      delivered.insert(PathBuf::from(cursor.read::<String>(0)?));
+    }
+    drop(cursor);
+
+    Ok(Self {
+      path,
+      connection,
+      delivered,
+      pending: 0,
+      committed: Instant::now(),
+      warnings: discarded.into_iter().collect(),
+    })
+  }
+
+  /// Failures since the last call, which the scan reports as warnings
+  pub fn take_warnings(&mut self) -> Vec<ScanWarning> {
+    mem::take(&mut self.warnings)
+  }
+
+  fn warn(&mut self, path: String, message: String) {
+    self.warnings.push(ScanWarning { path, message });
+  }
+
+  /// Paths an interrupted scan of the same folders already delivered
+  pub fn take_delivered(&mut self) -> HashSet<PathBuf> {
+    mem::take(&mut self.delivered)
+  }
+
+  fn insert(&mut self, path: &str) -> Result<(), ScanError> {
+    if self.pending == 0 {
+      self.connection.execute("BEGIN")?;
+    }
+    self.pending += 1;
+
+    let mut insert = self
+      .connection
+      .prepare("INSERT OR IGNORE INTO delivered (path) VALUES (?)")?;
+    insert.bind(1, path)?;
+    insert.next()?;
+
+    Ok(())
+  }
+
+  /// Records a delivered file. It only counts once a checkpoint commits it.
+  pub fn record(&mut self, path: &str) {
+    if let Err(e) = self.insert(path) {
+      self.warn(path.to_string(), format!("Failed to journal: {}", e));
+    }
+  }
+
+  /// Whether enough has been recorded since the last checkpoint
+  pub fn due(&self) -> bool {
+    self.pending >= CHECKPOINT_SIZE
+      || (self.pending > 0 && self.committed.elapsed() >= CHECKPOINT_INTERVAL)
+  }
+
+  /// Commits recorded paths. Only call this once everything recorded has
+  /// actually been handed over, not while songs wait in a batch.
+  pub fn checkpoint(&mut self) {
+    if self.pending == 0 {
+      return;
+    }
+
+    if let Err(e) = self.connection.execute("COMMIT") {
+      self.connection.execute("ROLLBACK").ok();
+      self.warn(
+        self.path.to_string_lossy().to_string(),
+        format!("Failed to commit scan journal: {}", e),
+      );
+    }
+    self.pending = 0;
+    self.committed = Instant::now();
+  }
+
+  /// Forgets every recorded path once a scan has gone through completely
+  pub fn finish(&mut self) {
+    self.checkpoint();
+    if let Err(e) = self.connection.execute("DELETE FROM delivered") {
+      self.warn(
+        self.path.to_string_lossy().to_string(),
+        format!("Failed to clear scan journal: {}", e),
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs};
+
+  use super::*;
+
+  fn journal_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(name);
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn delivered(dir: &Path, roots: &[PathBuf]) -> HashSet<PathBuf> {
+    ScanJournal::open(dir, roots).unwrap().take_delivered()
+  }
+
+  #[test]
+  fn resumes_with_committed_paths_only() {
+    let dir = journal_dir("moosync-journal-resume");
+    let roots = [dir.join("music")];
+
+    let mut journal = ScanJournal::open(&dir, &roots).unwrap();
+    journal.record("/music/a.flac");
+    journal.checkpoint();
+    journal.record("/music/b.flac");
+    drop(journal);
+
+    assert_eq!(
+      delivered(&dir, &roots),
+      HashSet::from([PathBuf::from("/music/a.flac")])
+    );
+
+    let mut journal = ScanJournal::open(&dir, &roots).unwrap();
+    journal.finish();
+    assert!(journal.take_warnings().is_empty());
+    drop(journal);
+    assert!(delivered(&dir, &roots).is_empty());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn discards_journals_of_other_folders() {
+    let dir = journal_dir("moosync-journal-roots");
+
+    let mut journal = ScanJournal::open(&dir, &[dir.join("music")]).unwrap();
+    journal.record("/music/a.flac");
+    journal.checkpoint();
+    drop(journal);
+
+    assert!(delivered(&dir, &[dir.join("podcasts")]).is_empty());
+    assert!(delivered(&dir, &[dir.join("music")]).is_empty());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn starts_over_from_unreadable_journals() {
+    let dir = journal_dir("moosync-journal-unreadable");
+    fs::write(dir.join(JOURNAL_FILE), "not a database").unwrap();
+
+    let mut journal = ScanJournal::open(&dir, &[dir.join("music")]).unwrap();
+    let warnings = journal.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].path.ends_with(JOURNAL_FILE));
+    assert!(journal.take_delivered().is_empty());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+}
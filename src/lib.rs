@@ -3,30 +3,431 @@
 #[macro_use]
 extern crate napi_derive;
 
+mod aggregate;
+mod cache;
+mod credits;
 mod database;
+mod emitter;
 mod error;
+mod filters;
+mod journal;
 mod playlist_scanner;
+mod scan_control;
 mod song_scanner;
 mod structs;
 mod utils;
 
-use std::{path::PathBuf, str::FromStr, sync::mpsc::channel, thread::spawn};
+use std::{
+  path::PathBuf,
+  str::FromStr,
+  sync::{
+    mpsc::{channel, Receiver, RecvTimeoutError},
+    Arc,
+  },
+  thread::spawn,
+  time::Duration,
+};
 
-use napi::{
-  bindgen_prelude::Undefined,
-  threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode},
-  JsFunction,
+use aggregate::Aggregator;
+use cache::ScanCache;
+use credits::CreditParser;
+use database::DatabaseWriter;
+use emitter::{
+  CallbackEmitter, Completion, ScanEmitter, ScanPhase, SongBatch, StreamEmitter, WritingEmitter,
 };
+use error::ScanError;
+use filters::PathFilter;
+use journal::ScanJournal;
+use napi::{Either, Env, JsFunction, JsObject};
 use playlist_scanner::PlaylistScanner;
+use scan_control::{ScanControl, ScanEventStream, ScanHandle};
 use song_scanner::SongScanner;
-use structs::{Playlist, SongWithLen};
+use structs::{
+  MovedSong, ScanConfig, ScanOptions, ScanProgress, ScanSummary, ScanWarning, Song, SongWithLen,
+  TraversalPolicy,
+};
 use threadpool::ThreadPool;
+use utils::get_files_recursively;
+pub use utils::{get_extensions, register_extensions, unregister_extensions};
+
+const DEFAULT_BATCH_SIZE: u32 = 100;
+
+impl ScanConfig {
+  fn new(
+    dir: Either<String, Vec<String>>,
+    thumbnail_dir: String,
+    database_dir: String,
+    artist_split: String,
+    threads: i32,
+    force: bool,
+    options: &ScanOptions,
+  ) -> Result<Self, napi::Error> {
+    let cpus = num_cpus::get();
+    let threads = if threads <= 0 || threads as usize > cpus {
+      cpus
+    } else {
+      threads as usize
+    };
+
+    let dirs = match dir {
+      Either::A(dir) => vec![dir],
+      Either::B(dirs) => dirs,
+    };
+
+    Ok(Self {
+      dirs: dirs.iter().map(PathBuf::from).collect(),
+      exclude: Arc::new(PathFilter::new(
+        options.exclude.as_deref().unwrap_or_default(),
+      )?),
+      traversal: TraversalPolicy {
+        follow_symlinks: options.follow_symlinks.unwrap_or(true),
+        same_filesystem: options.same_filesystem.unwrap_or(false),
+        max_depth: options.max_depth.map(|depth| depth as usize),
+      },
+      sniff_content: options.sniff_content.unwrap_or(false),
+      hash_content: options.hash_content.unwrap_or(false),
+      database_schema: options.database_schema.clone().unwrap_or_default(),
+      cache: options.cache.unwrap_or(false),
+      resume: options.resume.unwrap_or(false),
+      write_database: options.write_database.unwrap_or(false),
+      aggregate: options.aggregate.unwrap_or(false)
+        || options.on_albums.is_some()
+        || options.on_artists.is_some(),
+      thumbnail_dir: PathBuf::from_str(thumbnail_dir.as_str())?,
+      database_dir: PathBuf::from_str(database_dir.as_str())?,
+      credits: Arc::new(CreditParser::new(
+        &artist_split,
+        options.artist_credits.as_ref(),
+      )?),
+      threads,
+      force,
+      batch_size: (options.on_song_batch.is_some()
+        || options.batch_size.is_some()
+        || options.batch_window_ms.is_some())
+      .then(|| options.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1) as usize),
+      batch_window: options
+        .batch_window_ms
+        .map(|ms| Duration::from_millis(ms as u64)),
+    })
+  }
+}
+
+fn run_scan(config: ScanConfig, control: ScanControl, emitter: &mut impl ScanEmitter) {
+  if !config.write_database {
+    return drive_scan(config, control, emitter);
+  }
+
+  match DatabaseWriter::open(config.database_dir.clone()) {
+    Ok(writer) => drive_scan(config, control, &mut WritingEmitter::new(writer, emitter)),
+    Err(e) => emitter.failed(ScanPhase::Songs, e),
+  }
+}
+
+fn warn(emitter: &mut impl ScanEmitter, summary: &mut ScanSummary, warnings: Vec<ScanWarning>) {
+  for warning in warnings {
+    emitter.warning(warning.clone());
+    summary.warnings.push(warning);
+  }
+}
+
+/// Reports what went wrong in the scan cache and journal. Neither stops the
+/// scan, they only make the next one slower.
+fn report_store_warnings(
+  emitter: &mut impl ScanEmitter,
+  summary: &mut ScanSummary,
+  cache: &Option<ScanCache>,
+  journal: Option<&mut ScanJournal>,
+) {
+  if let Some(cache) = cache {
+    warn(emitter, summary, cache.take_warnings());
+  }
+  if let Some(journal) = journal {
+    warn(emitter, summary, journal.take_warnings());
+  }
+}
+
+/// Lets songs queued before a failure finish, so that no pool job is still
+/// running once the scan has reported its end. Every sender but the jobs'
+/// own must be gone already.
+fn wind_down(rx_song: Receiver<Result<Song, ScanError>>, song_pool: &ThreadPool) {
+  for _ in rx_song {}
+  song_pool.join();
+}
+
+fn drive_scan(config: ScanConfig, control: ScanControl, emitter: &mut impl ScanEmitter) {
+  let (tx_song, rx_song) = channel();
+  let (tx_playlist, rx_playlist) = channel();
+
+  let (tx_files, rx_files) = channel();
+
+  let mut song_pool = ThreadPool::new(config.threads);
+
+  let walker = {
+    let dirs = config.dirs.clone();
+    let exclude = config.exclude.clone();
+    let traversal = config.traversal.clone();
+    let sniff_content = config.sniff_content;
+    let control = control.clone();
+    let threads = config.threads;
+    spawn(move || {
+      get_files_recursively(
+        &dirs,
+        exclude,
+        &traversal,
+        sniff_content,
+        &control,
+        threads,
+        tx_files,
+      )
+    })
+  };
+
+  let mut journal = if config.resume {
+    match ScanJournal::open(&config.thumbnail_dir, &config.dirs) {
+      Ok(journal) => Some(journal),
+      Err(e) => {
+        emitter.failed(ScanPhase::Songs, e);
+        return;
+      }
+    }
+  } else {
+    None
+  };
+  let delivered = journal
+    .as_mut()
+    .map(ScanJournal::take_delivered)
+    .unwrap_or_default();
+
+  let song_scanner = match SongScanner::new(&mut song_pool, &config, delivered, control.clone()) {
+    Ok(song_scanner) => song_scanner,
+    Err(e) => {
+      emitter.failed(ScanPhase::Songs, e);
+      return;
+    }
+  };
+
+  let discovery = match song_scanner.start(&config.dirs, rx_files, tx_song.clone(), config.force) {
+    Ok(discovery) => discovery,
+    Err(e) => {
+      drop((song_scanner, tx_song));
+      walker.join().ok();
+      wind_down(rx_song, &song_pool);
+      emitter.failed(ScanPhase::Songs, e);
+      return;
+    }
+  };
+
+  // The walker stops on its own once its channel is gone
+  walker.join().ok();
+
+  let mut len = discovery.len;
+  let mut aggregator = config.aggregate.then(Aggregator::default);
+  let mut summary = ScanSummary {
+    resumed: discovery.resumed as u32,
+    ..Default::default()
+  };
+
+  let cache = song_scanner.cache();
+  warn(emitter, &mut summary, discovery.warnings);
+  report_store_warnings(emitter, &mut summary, &cache, journal.as_mut());
+
+  for skipped in discovery.skipped {
+    emitter.skipped(skipped.clone());
+    summary.skipped.push(skipped);
+  }
+
+  // Songs left alone by an incremental scan, or delivered by an earlier
+  // run, never reach the aggregator
+  let partial = if summary.resumed > 0 {
+    Some(&config.thumbnail_dir)
+  } else if !config.force && !config.database_dir.as_os_str().is_empty() {
+    Some(&config.database_dir)
+  } else {
+    None
+  };
+  if let (Some(_), Some(dir)) = (&aggregator, partial) {
+    let warning = ScanWarning {
+      path: dir.to_string_lossy().to_string(),
+      message: "Albums and artists only cover the songs delivered by this scan, pass `force` \
+        for complete ones"
+        .to_string(),
+    };
+    warn(emitter, &mut summary, vec![warning]);
+  }
+
+  let missing = song_scanner.missing();
+
+  // Start playlist scanner
+  let playlist_scanner = PlaylistScanner::new(config.thumbnail_dir, song_scanner, control.clone());
+
+  let res = match playlist_scanner.start(discovery.playlists, tx_song, tx_playlist) {
+    Ok(res) => res,
+    Err(e) => {
+      drop(playlist_scanner);
+      wind_down(rx_song, &song_pool);
+      emitter.failed(ScanPhase::Playlists, e);
+      return;
+    }
+  };
+
+  for playlist in rx_playlist {
+    control.wait_if_paused();
+    match playlist {
+      Ok(_) => summary.playlists += 1,
+      Err(_) => summary.playlist_errors += 1,
+    }
+    emitter.playlist(playlist);
+  }
+
+  len += res;
+  summary.total = len as u32;
+  emitter.progress(ScanProgress {
+    current: 0,
+    total: summary.total,
+  });
+
+  let mut batch = config
+    .batch_size
+    .map(|size| SongBatch::new(size, config.batch_window));
+  let mut current_song = 0;
+
+  // Keep draining after a cancel so that pool jobs never hit a closed channel
+  loop {
+    let song = match batch.as_ref().and_then(|b| b.time_left()) {
+      Some(timeout) => match rx_song.recv_timeout(timeout) {
+        Ok(song) => song,
+        Err(RecvTimeoutError::Timeout) => {
+          if let Some(batch) = batch.as_mut() {
+            batch.flush(emitter);
+          }
+          continue;
+        }
+        Err(RecvTimeoutError::Disconnected) => break,
+      },
+      None => match rx_song.recv() {
+        Ok(song) => song,
+        Err(_) => break,
+      },
+    };
+
+    current_song += 1;
+
+    control.wait_if_paused();
+    if control.is_cancelled() {
+      continue;
+    }
+
+    if let Ok(song) = &song {
+      if let (Some(from), Some(to)) = (&song.moved_from, &song.path) {
+        emitter.moved(MovedSong {
+          _id: song._id.clone(),
+          from: from.clone(),
+          to: to.clone(),
+        });
+      }
+    }
+
+    if let (Some(aggregator), Ok(song)) = (aggregator.as_mut(), &song) {
+      aggregator.add(song);
+    }
+
+    // Playlists aren't journaled, so their songs are delivered again with them
+    let journaled = match &song {
+      Ok(song) if song.playlist_id.is_none() => song.path.clone(),
+      _ => None,
+    };
+
+    let song = song.map(|v| SongWithLen {
+      song: v,
+      size: len as u32,
+      current: current_song,
+    });
+
+    match (song, batch.as_mut()) {
+      (Ok(song), Some(batch)) => {
+        summary.count(&song.song);
+        batch.push(song, emitter);
+      }
+      (Ok(song), None) => {
+        summary.count(&song.song);
+        emitter.song(Ok(song));
+      }
+      (Err(e), batch) => {
+        summary.song_errors += 1;
+
+        // Errors are not batched, so flush first to keep them in order
+        if let Some(batch) = batch {
+          batch.flush(emitter);
+        }
+        emitter.song(Err(e));
+      }
+    }
+
+    if let Some(journal) = journal.as_mut() {
+      if let Some(path) = journaled {
+        journal.record(&path);
+      }
+
+      // Songs still waiting in a batch haven't been handed over yet
+      if journal.due() && batch.as_ref().is_none_or(SongBatch::is_empty) && emitter.checkpoint() {
+        journal.checkpoint();
+      }
+    }
+    report_store_warnings(emitter, &mut summary, &cache, journal.as_mut());
+  }
+
+  if let Some(batch) = batch.as_mut() {
+    batch.flush(emitter);
+  }
+
+  drop(playlist_scanner);
+  song_pool.join();
+
+  // Rows no added file claimed as a move are gone. A cancelled scan may not
+  // have looked at every added file, so nothing is reported then.
+  if !control.is_cancelled() {
+    let removed = missing.take_removed();
+    if !removed.is_empty() {
+      summary.removed = removed.len() as u32;
+      emitter.removed(removed);
+    }
+  }
+
+  if let (Some(mut aggregator), false) = (aggregator, control.is_cancelled()) {
+    emitter.albums(aggregator.albums());
+    emitter.artists(aggregator.artists());
+  }
+
+  let status = if control.is_cancelled() {
+    "cancelled"
+  } else {
+    "completed"
+  };
+  summary.status = status.to_string();
+
+  // Paths which were recorded but never committed are rolled back when the
+  // journal is dropped, and delivered again next time. The stream can still
+  // be abandoned while it waits to be read, so the cancel is checked after.
+  if let Some(journal) = journal.as_mut() {
+    if emitter.checkpoint() {
+      if control.is_cancelled() {
+        journal.checkpoint();
+      } else {
+        journal.finish();
+      }
+    }
+  }
+  report_store_warnings(emitter, &mut summary, &cache, journal.as_mut());
+
+  emitter.end(summary);
+}
 
 #[napi(
-  ts_args_type = "dir: string, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, callback_song: (err: null | Error, result: SongWithLen) => void, callback_playlist: (err: null | Error, result: Playlist) => void, callback_end: (err: null | Error) => void"
+  ts_args_type = "dir: string | Array<string>, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, callback_song: (err: null | Error, result: SongWithLen) => void, callback_playlist: (err: null | Error, result: Playlist) => void, callback_end: (err: null | Error, result: ScanSummary) => void, options?: ScanOptions"
 )]
+#[allow(clippy::too_many_arguments)]
 pub fn scan_files(
-  dir: String,
+  dir: Either<String, Vec<String>>,
   thumbnail_dir: String,
   database_dir: String,
   artist_split: String,
@@ -35,98 +436,112 @@ pub fn scan_files(
   callback_songs: JsFunction,
   callback_playlists: JsFunction,
   callback_end: JsFunction,
-) -> Result<Undefined, napi::Error> {
-  let thumbnail_dir = PathBuf::from_str(thumbnail_dir.as_str())?;
-  let dir = PathBuf::from_str(dir.as_str())?;
-  let database_dir = PathBuf::from_str(database_dir.as_str())?;
+  options: Option<ScanOptions>,
+) -> Result<ScanHandle, napi::Error> {
+  let mut options = options.unwrap_or_default();
+  let config = ScanConfig::new(
+    dir,
+    thumbnail_dir,
+    database_dir,
+    artist_split,
+    threads,
+    force,
+    &options,
+  )?;
 
-  let tsfn_songs: ThreadsafeFunction<SongWithLen, ErrorStrategy::CalleeHandled> =
-    callback_songs.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+  let mut emitter = CallbackEmitter::new(
+    callback_songs,
+    callback_playlists,
+    Completion::Callback(callback_end.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?),
+    &mut options,
+  )?;
 
-  let tsfn_playlists: ThreadsafeFunction<Playlist, ErrorStrategy::CalleeHandled> =
-    callback_playlists.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+  let control = ScanControl::new();
+  let handle = ScanHandle::new(control.clone());
 
-  let tsfn_end: ThreadsafeFunction<(), ErrorStrategy::CalleeHandled> =
-    callback_end.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+  spawn(move || run_scan(config, control, &mut emitter));
 
-  spawn(move || {
-    let (tx_song, rx_song) = channel();
-    let (tx_playlist, rx_playlist) = channel();
-
-    let cpus = num_cpus::get();
-    let thread_count = if threads <= 0 {
-      cpus
-    } else if threads as usize > cpus {
-      cpus
-    } else {
-      threads as usize
-    };
+  Ok(handle)
+}
 
-    let mut song_pool = ThreadPool::new(thread_count);
-
-    let song_scanner = SongScanner::new(
-      dir.clone(),
-      &mut song_pool,
-      database_dir.clone(),
-      thumbnail_dir.clone(),
-      artist_split,
-    );
-
-    let res = song_scanner.start(tx_song.clone(), force);
-    if res.is_err() {
-      let cloned = tsfn_songs.clone();
-      cloned.call(
-        Err(res.err().unwrap().into()),
-        ThreadsafeFunctionCallMode::Blocking,
-      );
-      return;
-    }
+/// Same as `scanFiles`, but resolves with the scan summary instead of calling
+/// `callback_end`. Rejects if the scan could not be completed.
+#[napi(
+  ts_args_type = "dir: string | Array<string>, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, callback_song: (err: null | Error, result: SongWithLen) => void, callback_playlist: (err: null | Error, result: Playlist) => void, options?: ScanOptions",
+  ts_return_type = "Promise<ScanSummary>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn scan_files_async(
+  env: Env,
+  dir: Either<String, Vec<String>>,
+  thumbnail_dir: String,
+  database_dir: String,
+  artist_split: String,
+  threads: i32,
+  force: bool,
+  callback_songs: JsFunction,
+  callback_playlists: JsFunction,
+  options: Option<ScanOptions>,
+) -> Result<JsObject, napi::Error> {
+  let mut options = options.unwrap_or_default();
+  let config = ScanConfig::new(
+    dir,
+    thumbnail_dir,
+    database_dir,
+    artist_split,
+    threads,
+    force,
+    &options,
+  )?;
 
-    let mut len = res.unwrap();
+  let (deferred, promise) = env.create_deferred()?;
+  let mut emitter = CallbackEmitter::new(
+    callback_songs,
+    callback_playlists,
+    Completion::Promise(Some(deferred)),
+    &mut options,
+  )?;
 
-    // Start playlist scanner
-    let playlist_scanner = PlaylistScanner::new(dir, thumbnail_dir, song_scanner);
+  spawn(move || run_scan(config, ScanControl::new(), &mut emitter));
 
-    let res = playlist_scanner.start(tx_song, tx_playlist);
-    if res.is_err() {
-      let cloned = tsfn_playlists.clone();
-      cloned.call(
-        Err(res.err().unwrap().into()),
-        ThreadsafeFunctionCallMode::Blocking,
-      );
-      return;
-    }
+  Ok(promise)
+}
 
-    for playlist in rx_playlist {
-      let cloned = tsfn_playlists.clone();
-      cloned.call(
-        playlist.map_err(|e| e.into()),
-        ThreadsafeFunctionCallMode::Blocking,
-      );
-    }
+/// Starts a scan and returns an async iterable of typed scan events. Breaking
+/// out of a `for await` loop aborts the scan.
+#[napi(
+  ts_args_type = "dir: string | Array<string>, thumbnailDir: string, databaseDir: string, artistSplit: string, threads: number, force: boolean, options?: ScanOptions",
+  ts_return_type = "ScanEventStream"
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn scan_files_stream(
+  env: Env,
+  dir: Either<String, Vec<String>>,
+  thumbnail_dir: String,
+  database_dir: String,
+  artist_split: String,
+  threads: i32,
+  force: bool,
+  options: Option<ScanOptions>,
+) -> Result<JsObject, napi::Error> {
+  let options = options.unwrap_or_default();
+  let config = ScanConfig::new(
+    dir,
+    thumbnail_dir,
+    database_dir,
+    artist_split,
+    threads,
+    force,
+    &options,
+  )?;
 
-    len += res.unwrap();
-
-    let mut current_song = 1;
-
-    for song in rx_song {
-      let cloned = tsfn_songs.clone();
-      cloned.call(
-        song.map_err(|e| e.into()).map(|v| SongWithLen {
-          song: v,
-          size: len as u32,
-          current: current_song,
-        }),
-        ThreadsafeFunctionCallMode::Blocking,
-      );
-      current_song += 1;
-    }
+  let control = ScanControl::new();
+  let stream = ScanEventStream::new(control.clone());
+  let mut emitter = StreamEmitter::new(stream.state());
 
-    drop(playlist_scanner);
-    song_pool.join();
+  let stream = stream.into_js(env)?;
 
-    tsfn_end.call(Ok(()), ThreadsafeFunctionCallMode::Blocking);
-  });
+  spawn(move || run_scan(config, control, &mut emitter));
 
-  Ok(())
+  Ok(stream)
 }
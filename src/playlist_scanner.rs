@@ -8,27 +8,26 @@ use std::{
 
 use substring::Substring;
 
-use uuid::Uuid;
-
 use crate::{
   error::ScanError,
+  scan_control::ScanControl,
   song_scanner::SongScanner,
   structs::{Artists, Playlist, Song},
-  utils::{check_directory, get_files_recursively},
+  utils::{artist_id, check_directory, stable_id},
 };
 
 pub struct PlaylistScanner<'a> {
-  dir: PathBuf,
   song_scanner: SongScanner<'a>,
   thumbnail_dir: PathBuf,
+  control: ScanControl,
 }
 
 impl<'a> PlaylistScanner<'a> {
-  pub fn new(dir: PathBuf, thumbnail_dir: PathBuf, song_scanner: SongScanner<'a>) -> Self {
+  pub fn new(thumbnail_dir: PathBuf, song_scanner: SongScanner<'a>, control: ScanControl) -> Self {
     Self {
-      dir,
       thumbnail_dir,
       song_scanner,
+      control,
     }
   }
 
@@ -38,11 +37,13 @@ impl<'a> PlaylistScanner<'a> {
 
   fn parse_artists(&self, artists: Option<String>) -> Vec<Artists> {
     let mut ret: Vec<Artists> = vec![];
-    if artists.is_some() {
-      for artist in artists.unwrap().split(";") {
+    if let Some(artists) = artists {
+      for artist in artists.split(';') {
         ret.push(Artists {
-          artist_id: Uuid::new_v4().to_string(),
+          artist_id: artist_id(artist),
           artist_name: artist.to_string(),
+          role: None,
+          external_ids: None,
         })
       }
     }
@@ -61,103 +62,94 @@ impl<'a> PlaylistScanner<'a> {
     let mut artists: Option<String> = None;
     let mut playlist_title: String = "".to_string();
 
-    let playlist_id = Uuid::new_v4().to_string();
-    for line_res in lines {
-      if let Ok(mut line) = line_res {
-        if line.starts_with("#EXTINF:") {
-          let metadata = line.substring(8, line.len());
-          let split_index = metadata.find(",").unwrap_or_default();
-
-          duration = Some(metadata.substring(0, split_index).parse::<f64>()?);
-
-          let non_duration = metadata.substring(split_index + 1, metadata.len());
-
-          let mut artists_str = "";
-          let mut title_str = "";
-
-          let separator_with_space = non_duration.find(" - ");
-          if separator_with_space.is_some() {
-            (artists_str, title_str) = non_duration.split_at(separator_with_space.unwrap() + 1);
-          } else {
-            let separator_without_space = non_duration.find("-");
-            if separator_without_space.is_some() {
-              (artists_str, title_str) = non_duration.split_at(separator_without_space.unwrap());
-            } else {
-              title_str = non_duration;
-            }
-          }
+    let playlist_id = stable_id("playlist", &path.to_string_lossy());
+    for line in lines {
+      // Unreadable lines are skipped, the rest of the playlist still counts
+      let Ok(mut line) = line else {
+        continue;
+      };
 
-          artists = Some(artists_str.trim().to_string());
-          title = Some(title_str.replacen("-", "", 1).trim().to_string());
+      if line.starts_with("#EXTINF:") {
+        let metadata = line.substring(8, line.len());
+        let split_index = metadata.find(",").unwrap_or_default();
 
-          println!("{:?} {:?}", artists, title);
+        duration = Some(metadata.substring(0, split_index).parse::<f64>()?);
 
-          continue;
-        }
+        let non_duration = metadata.substring(split_index + 1, metadata.len());
 
-        if line.starts_with("#MOOSINF:") {
-          song_type = Some(line.substring(9, line.len()).to_string());
-          continue;
-        }
+        let (artists_str, title_str) = if let Some(separator_with_space) = non_duration.find(" - ")
+        {
+          non_duration.split_at(separator_with_space + 1)
+        } else if let Some(separator_without_space) = non_duration.find('-') {
+          non_duration.split_at(separator_without_space)
+        } else {
+          ("", non_duration)
+        };
 
-        if line.starts_with("#PLAYLIST:") {
-          playlist_title = line.substring(10, line.len()).to_string();
-          continue;
+        artists = Some(artists_str.trim().to_string());
+        title = Some(title_str.replacen("-", "", 1).trim().to_string());
+
+        println!("{:?} {:?}", artists, title);
+
+        continue;
+      }
+
+      if line.starts_with("#MOOSINF:") {
+        song_type = Some(line.substring(9, line.len()).to_string());
+        continue;
+      }
+
+      if line.starts_with("#PLAYLIST:") {
+        playlist_title = line.substring(10, line.len()).to_string();
+        continue;
+      }
+
+      if !line.starts_with("#") {
+        if line.starts_with("file://") {
+          line = line[8..].to_string();
         }
 
-        if !line.starts_with("#") {
-          if line.starts_with("file://") {
-            line = line[8..].to_string();
+        let mut song = Song::default();
+
+        let s_type = song_type.clone();
+
+        song.song_type = s_type.unwrap_or("LOCAL".to_string());
+
+        if song.song_type == "LOCAL" {
+          let mut path_parsed = PathBuf::from(line.as_str());
+          if path_parsed.is_relative() {
+            path_parsed = path.parent().unwrap().join(path_parsed).canonicalize()?;
           }
 
-          let mut song = Song::default();
-
-          let s_type = song_type.clone();
-
-          song.song_type = s_type.unwrap_or("LOCAL".to_string());
-          song._id = Uuid::new_v4().to_string();
-
-          if song.song_type == "LOCAL" {
-            let song_path = PathBuf::from_str(line.as_str());
-            if let Ok(mut path_parsed) = song_path {
-              if path_parsed.is_relative() {
-                path_parsed = path.parent().unwrap().join(path_parsed).canonicalize()?;
-              }
-
-              if !path_parsed.exists() {
-                artists = None;
-                duration = None;
-                title = None;
-                song_type = None;
-                continue;
-              }
-
-              let metadata = fs::metadata(&path_parsed)?;
-              song.size = Some(metadata.len() as u32);
-              song.path = Some(path_parsed.to_string_lossy().to_string());
-            }
-
-            if song.path.is_none() {
-              song.path = Some(line);
-            }
-
-            song.playback_url = None;
-          } else {
-            song._id = format!("{}:{}", song.song_type, line);
-            song.playback_url = Some(line);
+          if !path_parsed.exists() {
+            artists = None;
+            duration = None;
+            title = None;
+            song_type = None;
+            continue;
           }
 
-          song.artists = self.parse_artists(artists);
-          song.duration = duration;
-          song.title = title;
-          song.playlist_id = Some(playlist_id.clone());
-          songs.push(song);
+          let metadata = fs::metadata(&path_parsed)?;
+          song.size = Some(metadata.len() as u32);
+          song._id = stable_id("song", &path_parsed.to_string_lossy());
+          song.path = Some(path_parsed.to_string_lossy().to_string());
 
-          artists = None;
-          duration = None;
-          title = None;
-          song_type = None;
+          song.playback_url = None;
+        } else {
+          song._id = format!("{}:{}", song.song_type, line);
+          song.playback_url = Some(line);
         }
+
+        song.artists = self.parse_artists(artists);
+        song.duration = duration;
+        song.title = title;
+        song.playlist_id = Some(playlist_id.clone());
+        songs.push(song);
+
+        artists = None;
+        duration = None;
+        title = None;
+        song_type = None;
       }
     }
 
@@ -172,12 +164,13 @@ impl<'a> PlaylistScanner<'a> {
   }
 
   fn scan_song_in_pool(&self, tx_song: Sender<Result<Song, ScanError>>, s: Song) {
-    if s.song_type == "LOCAL" && s.path.is_some() {
+    if let (true, Some(path)) = (s.song_type == "LOCAL", &s.path) {
       self.song_scanner.scan_in_pool(
         tx_song,
         s.size.unwrap_or_default() as u64,
-        PathBuf::from_str(s.path.unwrap().as_str()).unwrap(),
+        PathBuf::from_str(path.as_str()).unwrap(),
         s.playlist_id,
+        None,
       )
     } else {
       tx_song
@@ -188,31 +181,35 @@ impl<'a> PlaylistScanner<'a> {
 
   pub fn start(
     &self,
+    playlist_list: Vec<PathBuf>,
     tx_song: Sender<Result<Song, ScanError>>,
     tx_playlist: Sender<Result<Playlist, ScanError>>,
   ) -> Result<usize, ScanError> {
     self.check_dirs()?;
 
-    let file_list = get_files_recursively(self.dir.clone())?;
-
     let mut len = 0;
 
-    println!("{:?}", file_list.playlist_list);
-
-    for playlist in file_list.playlist_list {
-      let playlist_scan_res = self.scan_playlist(&playlist);
-      if playlist_scan_res.is_err() {
-        tx_playlist
-          .send(Err(ScanError::String(format!(
-            "Failed to scan {}: {:?}",
-            playlist.display(),
-            playlist_scan_res.unwrap_err()
-          ))))
-          .expect("channel will be there waiting for the pool");
-        continue;
+    println!("{:?}", playlist_list);
+
+    for playlist in playlist_list {
+      self.control.wait_if_paused();
+      if self.control.is_cancelled() {
+        break;
       }
 
-      let (playlist_dets, songs) = playlist_scan_res.unwrap();
+      let (playlist_dets, songs) = match self.scan_playlist(&playlist) {
+        Ok(res) => res,
+        Err(e) => {
+          tx_playlist
+            .send(Err(ScanError::String(format!(
+              "Failed to scan {}: {:?}",
+              playlist.display(),
+              e
+            ))))
+            .expect("channel will be there waiting for the pool");
+          continue;
+        }
+      };
       tx_playlist
         .send(Ok(playlist_dets.clone()))
         .expect("channel will be there waiting for the pool");
@@ -0,0 +1,184 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc, Condvar, Mutex,
+};
+
+use napi::{CallContext, Env, JsObject, JsSymbol};
+
+use crate::emitter::StreamState;
+
+/// Shared state used to steer a running scan from JS.
+///
+/// Cloning is cheap and every clone observes the same flags, so the
+/// directory walker, pool jobs and playlist scanner can each hold one.
+#[derive(Default, Clone, Debug)]
+pub struct ScanControl {
+  cancelled: Arc<AtomicBool>,
+  paused: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl ScanControl {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+
+    // Wake up anything parked on a pause so it can observe the cancel. The
+    // lock is taken so the notify can't slip in before a waiter parks.
+    let (lock, cvar) = &*self.paused;
+    let _guard = lock.lock().unwrap();
+    cvar.notify_all();
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+
+  pub fn set_paused(&self, paused: bool) {
+    let (lock, cvar) = &*self.paused;
+    *lock.lock().unwrap() = paused;
+    cvar.notify_all();
+  }
+
+  pub fn is_paused(&self) -> bool {
+    *self.paused.0.lock().unwrap()
+  }
+
+  /// Blocks the calling thread while the scan is paused. Returns early if the
+  /// scan gets cancelled in the meantime.
+  pub fn wait_if_paused(&self) {
+    let (lock, cvar) = &*self.paused;
+    let mut paused = lock.lock().unwrap();
+    while *paused && !self.is_cancelled() {
+      paused = cvar.wait(paused).unwrap();
+    }
+  }
+}
+
+/// Handle returned by `scanFiles` which can be used to pause, resume or stop the scan.
+#[napi]
+pub struct ScanHandle {
+  control: ScanControl,
+}
+
+impl ScanHandle {
+  pub fn new(control: ScanControl) -> Self {
+    Self { control }
+  }
+}
+
+#[napi]
+impl ScanHandle {
+  /// Stops directory walking, skips queued songs and playlists, and fires
+  /// `callback_end` with a `cancelled` status once the scan has wound down.
+  #[napi]
+  pub fn abort(&self) {
+    self.control.cancel();
+  }
+
+  /// Parks directory walking, pool jobs and playlist parsing at their next
+  /// safe point. Songs already handed to JS are not sent again.
+  #[napi]
+  pub fn pause(&self) {
+    self.control.set_paused(true);
+  }
+
+  #[napi]
+  pub fn resume(&self) {
+    self.control.set_paused(false);
+  }
+
+  #[napi(getter)]
+  pub fn cancelled(&self) -> bool {
+    self.control.is_cancelled()
+  }
+
+  #[napi(getter)]
+  pub fn paused(&self) -> bool {
+    self.control.is_paused()
+  }
+}
+
+#[js_function(1)]
+fn return_this(ctx: CallContext) -> napi::Result<JsObject> {
+  Ok(ctx.this_unchecked())
+}
+
+/// Async iterable returned by `scanFilesStream`.
+#[napi]
+pub struct ScanEventStream {
+  control: ScanControl,
+  state: Arc<Mutex<StreamState>>,
+}
+
+impl ScanEventStream {
+  pub fn new(control: ScanControl) -> Self {
+    Self {
+      control,
+      state: Default::default(),
+    }
+  }
+
+  pub fn state(&self) -> Arc<Mutex<StreamState>> {
+    self.state.clone()
+  }
+
+  /// Wraps the stream in a JS object which also implements
+  /// `Symbol.asyncIterator`, so it can be used with `for await`.
+  pub fn into_js(self, env: Env) -> napi::Result<JsObject> {
+    let instance = self.into_instance(env)?;
+    let mut object = instance.as_object(env);
+
+    let async_iterator = env
+      .get_global()?
+      .get_named_property_unchecked::<JsObject>("Symbol")?
+      .get_named_property::<JsSymbol>("asyncIterator")?;
+    object.set_property(
+      async_iterator,
+      env.create_function("asyncIterator", return_this)?,
+    )?;
+
+    Ok(object)
+  }
+}
+
+#[napi]
+impl ScanEventStream {
+  #[napi(ts_return_type = "Promise<ScanIteratorResult>")]
+  pub fn next(&self, env: Env) -> napi::Result<JsObject> {
+    let (deferred, promise) = env.create_deferred()?;
+    self.state.lock().unwrap().next(deferred);
+    Ok(promise)
+  }
+
+  /// Called by `for await` when the loop exits early. Aborts the scan and
+  /// drops any events which were not consumed yet.
+  #[napi(js_name = "return", ts_return_type = "Promise<ScanIteratorResult>")]
+  pub fn finish(&self, env: Env) -> napi::Result<JsObject> {
+    self.control.cancel();
+
+    let mut state = self.state.lock().unwrap();
+    state.discard();
+
+    let (deferred, promise) = env.create_deferred()?;
+    state.next(deferred);
+    Ok(promise)
+  }
+
+  #[napi]
+  pub fn abort(&self) {
+    self.control.cancel();
+  }
+
+  #[napi]
+  pub fn pause(&self) {
+    self.control.set_paused(true);
+  }
+
+  #[napi]
+  pub fn resume(&self) {
+    self.control.set_paused(false);
+  }
+}
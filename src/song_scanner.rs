@@ -1,37 +1,88 @@
-use std::{path::PathBuf, sync::mpsc::Sender};
+use std::{
+  collections::HashSet,
+  fs, mem,
+  path::PathBuf,
+  sync::{
+    mpsc::{Receiver, Sender},
+    Arc,
+  },
+};
 
 use threadpool::ThreadPool;
 
 use crate::{
-  database::files_not_in_db,
+  cache::ScanCache,
+  credits::CreditParser,
+  database::{get_database, FileChange, MissingSongs, SongIndex},
   error::ScanError,
-  structs::Song,
-  utils::{check_directory, get_files_recursively, scan_file},
+  scan_control::ScanControl,
+  structs::{DatabaseSchema, DiscoveredFile, FileStat, ScanConfig, ScanWarning, SkippedPath, Song},
+  utils::{check_directory, hash_file, mtime_millis, scan_file},
 };
 
+// Discovered files are diffed against the database in chunks of this size,
+// so probing can start before discovery has finished
+const DATABASE_CHUNK: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct Discovery {
+  pub len: usize,
+  pub playlists: Vec<PathBuf>,
+  pub warnings: Vec<ScanWarning>,
+  pub skipped: Vec<SkippedPath>,
+  /// Songs an interrupted scan already delivered
+  pub resumed: usize,
+}
+
 pub struct SongScanner<'a> {
-  dir: PathBuf,
   pool: &'a mut ThreadPool,
   database_path: PathBuf,
   thumbnail_dir: PathBuf,
-  artist_split: String,
+  credits: Arc<CreditParser>,
+  hash_content: bool,
+  database_schema: DatabaseSchema,
+  cache: Option<ScanCache>,
+  delivered: HashSet<PathBuf>,
+  missing: MissingSongs,
+  control: ScanControl,
 }
 
 impl<'a> SongScanner<'a> {
   pub fn new(
-    dir: PathBuf,
     pool: &'a mut ThreadPool,
-    database_path: PathBuf,
-    thumbnail_dir: PathBuf,
-    artist_split: String,
-  ) -> Self {
-    Self {
-      dir,
+    config: &ScanConfig,
+    delivered: HashSet<PathBuf>,
+    control: ScanControl,
+  ) -> Result<Self, ScanError> {
+    let cache = if config.cache {
+      Some(ScanCache::open(&config.thumbnail_dir, config.force)?)
+    } else {
+      None
+    };
+
+    Ok(Self {
       pool,
-      database_path,
-      thumbnail_dir,
-      artist_split,
-    }
+      database_path: config.database_dir.clone(),
+      thumbnail_dir: config.thumbnail_dir.clone(),
+      credits: config.credits.clone(),
+      hash_content: config.hash_content,
+      database_schema: config.database_schema.clone(),
+      cache,
+      delivered,
+      missing: MissingSongs::default(),
+      control,
+    })
+  }
+
+  /// Rows whose file was gone when the scan started and which no added file
+  /// has claimed as a move so far
+  pub fn missing(&self) -> MissingSongs {
+    self.missing.clone()
+  }
+
+  /// The scan cache, if enabled, whose failures the scan reports
+  pub fn cache(&self) -> Option<ScanCache> {
+    self.cache.clone()
   }
 
   fn check_dirs(&self) -> Result<(), ScanError> {
@@ -46,52 +97,186 @@ impl<'a> SongScanner<'a> {
     size: u64,
     path: PathBuf,
     playlist_id: Option<String>,
+    change: Option<FileChange>,
   ) {
     let thumbnail_dir = self.thumbnail_dir.clone();
-    let artist_split = self.artist_split.clone();
+    let credits = self.credits.clone();
+    let hash_content = self.hash_content;
+    let missing = self.missing.clone();
+    let cache = self.cache.clone();
+    let control = self.control.clone();
     self.pool.execute(move || {
-      let mut metadata = scan_file(
-        &path,
-        &thumbnail_dir,
-        &playlist_id,
-        size,
-        false,
-        &artist_split,
-      );
-      if metadata.is_err() {
-        metadata = scan_file(&path, &thumbnail_dir, &None, size, true, &artist_split);
+      control.wait_if_paused();
+
+      // Jobs queued before a cancel are drained without touching the file
+      if control.is_cancelled() {
+        return;
+      }
+
+      let mtime = fs::metadata(&path)
+        .ok()
+        .and_then(|metadata| mtime_millis(&metadata));
+      let hash = hash_content.then(|| hash_file(&path).ok()).flatten();
+
+      let cached = cache
+        .as_ref()
+        .and_then(|cache| cache.get(&path, size, mtime, hash.as_deref(), credits.key()));
+
+      let mut metadata = match cached {
+        Some(mut song) => {
+          song.playlist_id = playlist_id;
+          song.hash = hash;
+          Ok(song)
+        }
+        None => {
+          let mut metadata = scan_file(&path, &thumbnail_dir, &playlist_id, size, false, &credits);
+          if metadata.is_err() {
+            metadata = scan_file(&path, &thumbnail_dir, &None, size, true, &credits);
+          }
+
+          if let Ok(song) = metadata.as_mut() {
+            song.hash = hash;
+            if let Some(cache) = &cache {
+              cache.put(&path, size, mtime, song, credits.key());
+            }
+          }
+
+          metadata
+        }
+      };
+
+      if let Ok(song) = metadata.as_mut() {
+        match change {
+          Some(FileChange::Added) => {
+            let moved = missing
+              .claim_by_hash(&path, song.hash.as_deref())
+              .or_else(|| missing.claim_by_tags(song));
+
+            match moved {
+              Some(moved) => {
+                song._id = moved.id;
+                song.change = Some("moved".to_string());
+                song.moved_from = Some(moved.path);
+              }
+              None => song.change = Some("added".to_string()),
+            }
+          }
+          Some(FileChange::Modified { id }) => {
+            song._id = id;
+            song.change = Some("modified".to_string());
+          }
+          None => {}
+        }
       }
 
-      tx.send(metadata)
-        .expect("channel will be there waiting for the pool");
+      // Nobody is listening any more once the scan has failed
+      tx.send(metadata).ok();
     });
   }
 
+  fn dispatch(
+    &self,
+    database: Option<&SongIndex>,
+    pending: &mut Vec<FileStat>,
+    tx_song: &Sender<Result<Song, ScanError>>,
+  ) -> Result<usize, ScanError> {
+    let song_list = match database {
+      Some(database) => database
+        .diff(mem::take(pending), self.hash_content)?
+        .into_iter()
+        .map(|(file, change)| (file, Some(change)))
+        .collect(),
+      None => mem::take(pending)
+        .into_iter()
+        .map(|file| (file, None))
+        .collect::<Vec<_>>(),
+    };
+
+    let len = song_list.len();
+
+    for (file, change) in song_list {
+      self.control.wait_if_paused();
+      if self.control.is_cancelled() {
+        break;
+      }
+
+      self.scan_in_pool(tx_song.clone(), file.size, file.path, None, change);
+    }
+
+    Ok(len)
+  }
+
+  /// Queues songs as the walker finds them. Incremental scans check the
+  /// database in chunks, so probing starts before discovery has finished.
+  /// They also collect rows below `roots` whose file is gone up front, so
+  /// that added files can be matched against them as moves.
+  ///
+  /// An empty database path skips the database, so callers relying on the
+  /// cache alone get every song back. Songs an interrupted scan already
+  /// delivered are left out either way.
   pub fn start(
     &self,
+    roots: &[PathBuf],
+    rx_files: Receiver<DiscoveredFile>,
     tx_song: Sender<Result<Song, ScanError>>,
     force: bool,
-  ) -> Result<usize, ScanError> {
+  ) -> Result<Discovery, ScanError> {
     self.check_dirs()?;
 
-    let file_list = get_files_recursively(self.dir.clone())?;
+    if let Some(cache) = &self.cache {
+      cache.prune(roots)?;
+    }
 
-    let song_list = if !force {
-      files_not_in_db(self.database_path.clone(), file_list.file_list)?
+    let database = if force || self.database_path.as_os_str().is_empty() {
+      None
     } else {
-      file_list.file_list
+      let database = SongIndex::new(
+        get_database(self.database_path.clone())?,
+        &self.database_schema,
+      )?;
+      self.missing.set(database.missing_songs(roots)?);
+      Some(database)
     };
 
-    println!("{:?}", song_list);
+    let mut discovery = Discovery::default();
+    let mut seen = HashSet::new();
+    let mut pending = vec![];
 
-    let len = song_list.len();
+    for file in rx_files {
+      if self.control.is_cancelled() {
+        break;
+      }
+
+      match file {
+        DiscoveredFile::Song(FileStat { path, .. })
+          if self.delivered.contains(&path) && seen.insert(path.clone()) =>
+        {
+          discovery.resumed += 1
+        }
+        DiscoveredFile::Song(file) => {
+          if !seen.insert(file.path.clone()) {
+            continue;
+          }
 
-    for (file_path, size) in song_list {
-      self.scan_in_pool(tx_song.clone(), size, file_path, None);
+          pending.push(file);
+          if database.is_none() || pending.len() >= DATABASE_CHUNK {
+            discovery.len += self.dispatch(database.as_ref(), &mut pending, &tx_song)?;
+          }
+        }
+        DiscoveredFile::Playlist(path) => {
+          if seen.insert(path.clone()) {
+            discovery.playlists.push(path);
+          }
+        }
+        DiscoveredFile::Warning(warning) => discovery.warnings.push(warning),
+        DiscoveredFile::Skipped(skipped) => discovery.skipped.push(skipped),
+      }
     }
 
+    discovery.len += self.dispatch(database.as_ref(), &mut pending, &tx_song)?;
+
     drop(tx_song);
 
-    Ok(len)
+    Ok(discovery)
   }
 }
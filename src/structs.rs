@@ -1,8 +1,15 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-#[derive(Default, Debug)]
+use napi::JsFunction;
+use serde::{Deserialize, Serialize};
+
+use crate::{credits::CreditParser, filters::PathFilter};
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 #[napi(object)]
 pub struct Song {
+  /// Derived from the path, so it stays the same across scans
   pub _id: String,
   pub bitrate: Option<u32>,
   pub sample_rate: Option<u32>,
@@ -19,6 +26,37 @@ pub struct Song {
   #[napi(js_name = "track_no")]
   pub track_no: Option<String>,
 
+  #[napi(js_name = "disc_no")]
+  pub disc_no: Option<String>,
+
+  /// From the total tag, or the part after the slash of `track_no`
+  #[napi(js_name = "track_total")]
+  pub track_total: Option<u32>,
+  /// From the total tag, or the part after the slash of `disc_no`
+  #[napi(js_name = "disc_total")]
+  pub disc_total: Option<u32>,
+  pub composer: Option<String>,
+  pub conductor: Option<String>,
+  pub lyricist: Option<String>,
+  pub bpm: Option<f64>,
+  /// Musical key, such as `Am` or `8A`
+  #[napi(js_name = "initial_key")]
+  pub initial_key: Option<String>,
+  pub comment: Option<String>,
+  pub grouping: Option<String>,
+  /// Set when the file is flagged as part of a compilation
+  pub compilation: Option<bool>,
+  pub copyright: Option<String>,
+  /// Record label, or the publisher when there is none
+  pub label: Option<String>,
+  pub isrc: Option<String>,
+  #[napi(js_name = "title_sort")]
+  pub title_sort: Option<String>,
+  #[napi(js_name = "artist_sort")]
+  pub artist_sort: Option<String>,
+  #[napi(js_name = "album_sort")]
+  pub album_sort: Option<String>,
+
   #[napi(js_name = "playbackUrl")]
   pub playback_url: Option<String>,
 
@@ -31,11 +69,47 @@ pub struct Song {
   #[napi(js_name = "type")]
   pub song_type: String,
   pub playlist_id: Option<String>,
+  /// Container detected from the file contents, such as `mpeg` or `mp4`.
+  /// Files lofty can't read report their extension instead
+  pub container: Option<String>,
+  /// Last modification time of the file in milliseconds since the epoch
+  pub mtime: Option<f64>,
+  /// blake3 hash of the file's audio, when `ScanOptions.hashContent` is set
+  pub hash: Option<String>,
+  /// How the file differs from the database in incremental scans. Modified
+  /// and moved songs keep the `_id` of their database row.
+  #[napi(ts_type = "'added' | 'modified' | 'moved'")]
+  pub change: Option<String>,
+  /// Path stored in the database for a moved song
+  pub moved_from: Option<String>,
+  /// MusicBrainz, AcoustID and Discogs identifiers found in the tags
+  pub external_ids: Option<SongIds>,
+  /// Loudness normalization from ReplayGain tags, Opus R128 gains or ID3v2
+  /// RVA2 frames, in that order of preference
+  pub replay_gain: Option<ReplayGain>,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+#[napi(object)]
+pub struct ReplayGain {
+  /// In dB, relative to the ReplayGain reference of -18 LUFS
+  pub track_gain: Option<f64>,
+  /// Linear, 1.0 being full scale
+  pub track_peak: Option<f64>,
+  pub album_gain: Option<f64>,
+  pub album_peak: Option<f64>,
+  /// Where the values were read from. R128 gains are moved to the ReplayGain
+  /// reference level and carry no peaks
+  #[napi(ts_type = "'replaygain' | 'r128' | 'rva2'")]
+  pub source: String,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 #[napi(object)]
 pub struct Album {
+  /// Derived from the album artist and album name
   #[napi(js_name = "album_id")]
   pub album_id: String,
 
@@ -50,22 +124,151 @@ pub struct Album {
 
   #[napi(js_name = "album_artist")]
   pub album_artist: Option<String>,
+
+  pub external_ids: Option<AlbumIds>,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 #[napi(object)]
 pub struct Artists {
+  /// Derived from the artist name
   #[napi(js_name = "artist_id")]
   pub artist_id: String,
 
   #[napi(js_name = "artist_name")]
   pub artist_name: String,
+
+  /// How the artist is credited on the song. Only set when
+  /// `ScanOptions.artistCredits` is used
+  #[napi(ts_type = "'primary' | 'featured' | 'remixer'")]
+  pub role: Option<String>,
+
+  /// Only set when the tags hold exactly one id per artist of the song
+  pub external_ids: Option<ArtistIds>,
+}
+
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[napi(object)]
+pub struct SongIds {
+  pub musicbrainz_recording_id: Option<String>,
+  /// MusicBrainz id of the track on its release
+  pub musicbrainz_track_id: Option<String>,
+  pub musicbrainz_work_id: Option<String>,
+  /// Every artist id in the tags, in tag order
+  pub musicbrainz_artist_ids: Vec<String>,
+  pub acoustid_id: Option<String>,
+}
+
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[napi(object)]
+pub struct AlbumIds {
+  pub musicbrainz_release_id: Option<String>,
+  pub musicbrainz_release_group_id: Option<String>,
+  pub musicbrainz_album_artist_ids: Vec<String>,
+  pub discogs_release_id: Option<String>,
+  pub discogs_master_id: Option<String>,
+}
+
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[napi(object)]
+pub struct ArtistIds {
+  pub musicbrainz_artist_id: Option<String>,
+  pub discogs_artist_id: Option<String>,
+}
+
+/// Size and modification time of a file found on disk
+#[derive(Debug, Clone)]
+pub struct FileStat {
+  pub path: PathBuf,
+  pub size: u64,
+  /// Milliseconds since the epoch
+  pub mtime: Option<u64>,
+}
+
+/// Produced by the directory walker as it finds things
+#[derive(Debug)]
+pub enum DiscoveredFile {
+  Song(FileStat),
+  Playlist(PathBuf),
+  Warning(ScanWarning),
+  Skipped(SkippedPath),
+}
+
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct ScanWarning {
+  pub path: String,
+  pub message: String,
+}
+
+/// A song's place on an aggregated album
+#[derive(Debug)]
+#[napi(object)]
+pub struct AlbumTrack {
+  pub song_id: String,
+  pub title: Option<String>,
+  pub track_no: Option<u32>,
+  pub disc_no: Option<u32>,
+  pub duration: Option<f64>,
 }
 
+/// An album put together from every song of the scan which belongs to it
 #[derive(Debug)]
-pub struct FileList {
-  pub file_list: Vec<(PathBuf, u64)>,
-  pub playlist_list: Vec<PathBuf>,
+#[napi(object)]
+pub struct ScannedAlbum {
+  pub _id: String,
+  pub name: String,
+  /// The spelling most tracks use
+  pub album_artist: Option<String>,
+  /// The cover most tracks use
+  pub cover_path_high: Option<String>,
+  pub cover_path_low: Option<String>,
+  pub year_from: Option<u32>,
+  pub year_to: Option<u32>,
+  pub disc_count: u32,
+  /// Sorted by disc and track number
+  pub tracks: Vec<AlbumTrack>,
+}
+
+#[derive(Debug)]
+#[napi(object)]
+pub struct ScannedArtist {
+  pub _id: String,
+  pub name: String,
+  pub song_count: u32,
+  pub album_count: u32,
+}
+
+/// A database row whose file no longer exists
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct RemovedSong {
+  pub _id: String,
+  pub path: String,
+}
+
+/// A database row whose file turned up under a new path
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct MovedSong {
+  pub _id: String,
+  pub from: String,
+  pub to: String,
+}
+
+/// A file or folder left out of the scan on purpose
+#[derive(Debug, Clone)]
+#[napi(object)]
+pub struct SkippedPath {
+  pub path: String,
+  #[napi(ts_type = "'nomedia' | 'ignoreFile' | 'exclude'")]
+  pub reason: String,
+  /// The `.nomedia` or `.moosyncignore` file responsible, if any
+  pub source: Option<String>,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -83,3 +286,226 @@ pub struct SongWithLen {
   pub size: u32,
   pub current: u32,
 }
+
+#[derive(Debug, Clone, Default)]
+#[napi(object)]
+pub struct ScanSummary {
+  #[napi(ts_type = "'completed' | 'cancelled'")]
+  pub status: String,
+  pub total: u32,
+  pub songs: u32,
+  pub playlists: u32,
+  pub song_errors: u32,
+  pub playlist_errors: u32,
+  /// Songs whose database row is out of date
+  pub modified: u32,
+  /// Database rows whose file no longer exists
+  pub removed: u32,
+  /// Database rows whose file was found under a new path
+  pub moved: u32,
+  /// Files left out because an interrupted scan already delivered them
+  pub resumed: u32,
+  /// Paths which could not be read during discovery
+  pub warnings: Vec<ScanWarning>,
+  pub skipped: Vec<SkippedPath>,
+}
+
+impl ScanSummary {
+  pub fn count(&mut self, song: &Song) {
+    self.songs += 1;
+    match song.change.as_deref() {
+      Some("modified") => self.modified += 1,
+      Some("moved") => self.moved += 1,
+      _ => {}
+    }
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[napi(object)]
+pub struct ScanProgress {
+  pub current: u32,
+  pub total: u32,
+}
+
+/// Rows written to the database in one transaction, when the scanner writes
+/// songs itself
+#[derive(Debug)]
+#[napi(object)]
+pub struct WrittenBatch {
+  pub songs: Vec<String>,
+  pub playlists: Vec<String>,
+  pub progress: ScanProgress,
+}
+
+#[derive(Debug, Default)]
+#[napi(object)]
+pub struct ScanEvent {
+  #[napi(
+    js_name = "type",
+    ts_type = "'song' | 'songs' | 'playlist' | 'written' | 'albums' | 'artists' | 'moved' | 'removed' | 'warning' | 'skipped' | 'error' | 'progress' | 'end'"
+  )]
+  pub event_type: String,
+  pub song: Option<Song>,
+  pub songs: Option<Vec<SongWithLen>>,
+  pub playlist: Option<Playlist>,
+  pub written: Option<WrittenBatch>,
+  pub albums: Option<Vec<ScannedAlbum>>,
+  pub artists: Option<Vec<ScannedArtist>>,
+  pub moved: Option<MovedSong>,
+  pub removed: Option<Vec<RemovedSong>>,
+  pub warning: Option<ScanWarning>,
+  pub skipped: Option<SkippedPath>,
+  #[napi(ts_type = "'songs' | 'playlists'")]
+  pub phase: Option<String>,
+  pub message: Option<String>,
+  pub progress: Option<ScanProgress>,
+  pub summary: Option<ScanSummary>,
+}
+
+#[derive(Debug)]
+#[napi(object)]
+pub struct ScanIteratorResult {
+  pub value: Option<ScanEvent>,
+  pub done: bool,
+}
+
+#[derive(Default)]
+#[napi(object, object_to_js = false)]
+pub struct ScanOptions {
+  /// Receives songs in arrays instead of one `callback_song` call per song
+  #[napi(ts_type = "(err: null | Error, result: Array<SongWithLen>) => void")]
+  pub on_song_batch: Option<JsFunction>,
+  /// Maximum number of songs per batch. Defaults to 100
+  pub batch_size: Option<u32>,
+  /// Flushes a partial batch once its oldest song has waited this long
+  pub batch_window_ms: Option<u32>,
+  /// Globs for files and folders to leave out, such as `*.bak`. `**` matches
+  /// across folders. Patterns without a `/` are matched against the name only
+  pub exclude: Option<Vec<String>>,
+  /// Follow symlinks (and junctions on Windows). Defaults to true
+  pub follow_symlinks: Option<bool>,
+  /// Don't cross into other filesystems or drives below a root
+  pub same_filesystem: Option<bool>,
+  /// How many folder levels below each root are walked
+  pub max_depth: Option<u32>,
+  /// Probe files with a missing or unknown extension for audio content
+  pub sniff_content: Option<bool>,
+  /// Receives database rows whose file no longer exists below the scanned
+  /// folders, once the scan is done. Only called in incremental scans
+  #[napi(ts_type = "(err: null | Error, result: Array<RemovedSong>) => void")]
+  pub on_removed: Option<JsFunction>,
+  /// Receives database rows whose file turned up under a new path. The song
+  /// itself is still delivered, with `change` set to `moved` and its original
+  /// `_id`. Moves are matched by audio hash, or by title, duration, album and
+  /// artists for rows without a stored hash
+  #[napi(ts_type = "(err: null | Error, result: MovedSong) => void")]
+  pub on_moved: Option<JsFunction>,
+  /// Also compare audio hashes to find songs changed without a change in size
+  /// or modification time. Reads every file in full
+  pub hash_content: Option<bool>,
+  /// Where incremental scans look up known songs. Defaults to Moosync's own
+  /// `allsongs` table
+  pub database_schema: Option<DatabaseSchema>,
+  /// Keep extracted songs in a cache next to the thumbnails and serve files
+  /// which haven't changed from it. Forced scans refresh the cache without
+  /// reading it. Pass an empty `databaseDir` to rely on the cache alone
+  pub cache: Option<bool>,
+  /// Keep a journal of delivered files next to the thumbnails, so that a scan
+  /// which crashed or was aborted picks up where it stopped the next time the
+  /// same folders are scanned. Only files JS has actually received count as
+  /// delivered. Playlists are always read again
+  pub resume: Option<bool>,
+  /// Write songs, albums, artists, genres and playlists straight into the
+  /// database at `databaseDir`. Songs and playlists are then not handed to JS,
+  /// `onWritten` gets the ids of each committed batch instead
+  pub write_database: Option<bool>,
+  #[napi(ts_type = "(err: null | Error, result: WrittenBatch) => void")]
+  pub on_written: Option<JsFunction>,
+  /// Group the songs of this scan into albums and artists, delivered once
+  /// song scanning is done. Implied by `onAlbums` and `onArtists`. Songs an
+  /// incremental scan leaves alone, or a resumed one already delivered, are
+  /// not part of them, so such scans raise a warning. Use `force` for
+  /// complete albums
+  pub aggregate: Option<bool>,
+  #[napi(ts_type = "(err: null | Error, result: Array<ScannedAlbum>) => void")]
+  pub on_albums: Option<JsFunction>,
+  #[napi(ts_type = "(err: null | Error, result: Array<ScannedArtist>) => void")]
+  pub on_artists: Option<JsFunction>,
+  /// Read featured artists, collaborations and remixers out of artist tags and
+  /// titles instead of only splitting on `artistSplit`
+  pub artist_credits: Option<ArtistCredits>,
+}
+
+/// How `ScanOptions.artistCredits` splits credits such as `A ft. B, C x D`
+#[derive(Debug, Default, Clone)]
+#[napi(object)]
+pub struct ArtistCredits {
+  /// Names kept whole even though they contain a separator, such as
+  /// `Simon & Garfunkel`. Matched ignoring case
+  pub protected: Option<Vec<String>>,
+  /// Words introducing featured artists. Defaults to `featuring`, `feat.`,
+  /// `feat`, `ft.`, `ft` and `with`
+  pub featured_tokens: Option<Vec<String>>,
+  /// Separators between artists of the same role. Defaults to `&`, `,`, `x`,
+  /// `vs.` and `vs`. `artistSplit` always separates artists too
+  pub joint_tokens: Option<Vec<String>>,
+  /// Also read `(feat. X)` and `(X Remix)` credits from titles. Defaults to
+  /// true
+  pub from_title: Option<bool>,
+}
+
+/// Table and columns holding known songs. Unset names fall back to the
+/// `allsongs` layout
+#[derive(Debug, Default, Clone)]
+#[napi(object)]
+pub struct DatabaseSchema {
+  /// Defaults to `allsongs`
+  pub table: Option<String>,
+  /// Defaults to `_id`
+  pub id_column: Option<String>,
+  /// Defaults to `path`
+  pub path_column: Option<String>,
+  /// Defaults to `size`
+  pub size_column: Option<String>,
+  /// Modification time in milliseconds. Defaults to `mtime` if present
+  pub mtime_column: Option<String>,
+  /// blake3 hash of the file's audio. Defaults to `hash` if present
+  pub hash_column: Option<String>,
+  /// Used to match moved files without a hash. Defaults to `title` if present
+  pub title_column: Option<String>,
+  /// Defaults to `duration` if present
+  pub duration_column: Option<String>,
+  /// SQL expression limiting which rows count as known songs, such as
+  /// `type = 'LOCAL'`
+  pub filter: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraversalPolicy {
+  pub follow_symlinks: bool,
+  pub same_filesystem: bool,
+  pub max_depth: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct ScanConfig {
+  pub dirs: Vec<PathBuf>,
+  pub exclude: Arc<PathFilter>,
+  pub traversal: TraversalPolicy,
+  pub sniff_content: bool,
+  pub hash_content: bool,
+  pub database_schema: DatabaseSchema,
+  pub cache: bool,
+  pub resume: bool,
+  pub write_database: bool,
+  pub aggregate: bool,
+  pub thumbnail_dir: PathBuf,
+  pub database_dir: PathBuf,
+  pub credits: Arc<CreditParser>,
+  pub threads: usize,
+  pub force: bool,
+  /// `None` delivers songs one by one
+  pub batch_size: Option<usize>,
+  pub batch_window: Option<Duration>,
+}
@@ -1,18 +1,37 @@
+use jwalk::{ClientState, DirEntry, Parallelism, WalkDirGeneric};
 use lazy_static::lazy_static;
-use lofty::{read_from_path, Accessor, AudioFile, Picture, Probe, TaggedFileExt, ParseOptions};
+use lofty::{
+  id3::v2::{ChannelType, FrameValue, Id3v2Tag},
+  iff::{aiff::AiffFile, wav::WavFile},
+  mp4::{AtomData, AtomIdent, Ilst, Mp4File},
+  mpeg::MpegFile,
+  Accessor, AudioFile, FileType, ItemKey, LoftyError, ParseOptions, Picture, Probe, Tag,
+  TaggedFile, TaggedFileExt,
+};
 use regex::Regex;
+use sqlite3::Connection;
 use std::{
-  fs::{self},
+  collections::{HashMap, HashSet},
+  fs::{self, File},
+  io::{self, BufReader, Read, Seek, SeekFrom},
   num::NonZeroU32,
   path::{Path, PathBuf},
+  str::FromStr,
+  sync::{mpsc::Sender, Arc, Mutex, RwLock},
+  time::UNIX_EPOCH,
 };
-use uuid::Uuid;
 
 use image::ColorType;
 
 use crate::{
+  credits::{CreditParser, PRIMARY},
   error::ScanError,
-  structs::{Album, Artists, FileList, Song},
+  filters::{IgnoreFile, IgnoreStack, PathFilter},
+  scan_control::ScanControl,
+  structs::{
+    Album, AlbumIds, ArtistIds, Artists, DiscoveredFile, FileStat, ReplayGain, ScanWarning,
+    SkippedPath, Song, SongIds, TraversalPolicy,
+  },
 };
 use fast_image_resize as fr;
 
@@ -25,65 +44,583 @@ pub fn check_directory(dir: PathBuf) -> Result<(), ScanError> {
   Ok(())
 }
 
-pub fn get_files_recursively(dir: PathBuf) -> Result<FileList, ScanError> {
-  let mut file_list: Vec<(PathBuf, u64)> = vec![];
-  let mut playlist_list: Vec<PathBuf> = vec![];
+/// Opens a sqlite file in WAL mode and runs `setup` on it. The file only
+/// holds what a scan can rebuild, so one which fails is deleted and started
+/// over, with a warning for the scan to report.
+pub fn open_scratch_database(
+  path: &Path,
+  setup: impl Fn(&Connection) -> Result<(), ScanError>,
+) -> Result<(Connection, Option<ScanWarning>), ScanError> {
+  let open = || -> Result<Connection, ScanError> {
+    let connection = sqlite3::open(path)?;
+    connection.execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL")?;
+    setup(&connection)?;
+    Ok(connection)
+  };
 
-  lazy_static! {
-    static ref SONG_RE: Regex = Regex::new("flac|mp3|ogg|m4a|webm|wav|wv|aac|opus").unwrap();
-    static ref PLAYLIST_RE: Regex = Regex::new("m3u|m3u8").unwrap();
+  match open() {
+    Ok(connection) => Ok((connection, None)),
+    Err(e) => {
+      for suffix in ["", "-wal", "-shm"] {
+        fs::remove_file(format!("{}{}", path.to_string_lossy(), suffix)).ok();
+      }
+      let warning = ScanWarning {
+        path: path.to_string_lossy().to_string(),
+        message: format!("Discarded unreadable file: {}", e),
+      };
+      Ok((open()?, Some(warning)))
+    }
   }
+}
 
-  if !dir.exists() {
-    return Ok(FileList {
-      file_list,
-      playlist_list,
-    });
+const NOMEDIA_FILE: &str = ".nomedia";
+const IGNORE_FILE: &str = ".moosyncignore";
+
+fn skipped(path: &Path, reason: &str, source: Option<&Path>) -> DiscoveredFile {
+  DiscoveredFile::Skipped(SkippedPath {
+    path: path.to_string_lossy().to_string(),
+    reason: reason.to_string(),
+    source: source.map(|source| source.to_string_lossy().to_string()),
+  })
+}
+
+fn find_child<C: ClientState>(
+  children: &[jwalk::Result<DirEntry<C>>],
+  name: &str,
+) -> Option<PathBuf> {
+  children
+    .iter()
+    .flatten()
+    .find_map(|entry| (entry.file_type.is_file() && entry.file_name == name).then(|| entry.path()))
+}
+
+fn warning(path: &Path, message: impl ToString) -> DiscoveredFile {
+  DiscoveredFile::Warning(ScanWarning {
+    path: path.to_string_lossy().to_string(),
+    message: message.to_string(),
+  })
+}
+
+/// Identifies the physical file or folder behind a path, however it was reached
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FileId {
+  #[cfg(unix)]
+  Inode { dev: u64, ino: u64 },
+  #[cfg(not(unix))]
+  Path(PathBuf),
+}
+
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &fs::Metadata) -> FileId {
+  use std::os::unix::fs::MetadataExt;
+
+  FileId::Inode {
+    dev: metadata.dev(),
+    ino: metadata.ino(),
+  }
+}
+
+// Volume serials and file indices are not exposed on stable outside unix, so
+// fall back to the resolved path
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &fs::Metadata) -> FileId {
+  FileId::Path(dunce::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()))
+}
+
+#[cfg(unix)]
+fn device_id(_path: &Path, metadata: &fs::Metadata) -> Option<u64> {
+  use std::os::unix::fs::MetadataExt;
+
+  Some(metadata.dev())
+}
+
+// Outside unix the drive or share a path resolves to stands in for the device
+#[cfg(not(unix))]
+fn device_id(path: &Path, _metadata: &fs::Metadata) -> Option<u64> {
+  use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::Component,
+  };
+
+  let canonical = dunce::canonicalize(path).ok()?;
+  match canonical.components().next() {
+    Some(Component::Prefix(prefix)) => {
+      let mut hasher = DefaultHasher::new();
+      prefix.hash(&mut hasher);
+      Some(hasher.finish())
+    }
+    _ => None,
+  }
+}
+
+/// What a file is used for, decided by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+  Audio,
+  Playlist,
+  Lyrics,
+  Artwork,
+}
+
+impl FromStr for FileKind {
+  type Err = ScanError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "audio" => Ok(FileKind::Audio),
+      "playlist" => Ok(FileKind::Playlist),
+      "lyrics" => Ok(FileKind::Lyrics),
+      "artwork" => Ok(FileKind::Artwork),
+      _ => Err(ScanError::String(format!("Unknown file kind {}", s))),
+    }
+  }
+}
+
+// Audio covers every extension lofty can parse, plus a few it can't read at
+// all. Those still get an entry named after the file from `scan_file`.
+const DEFAULT_EXTENSIONS: &[(FileKind, &[&str])] = &[
+  (
+    FileKind::Audio,
+    &[
+      "aac", "aif", "aifc", "aiff", "afc", "ape", "flac", "m4a", "m4b", "m4p", "m4r", "mp1", "mp2",
+      "mp3", "mp4", "mpc", "mp+", "mpp", "oga", "ogg", "opus", "spx", "wav", "wave", "webm", "wv",
+      "dff", "dsf", "mka", "tta", "wma",
+    ],
+  ),
+  (FileKind::Playlist, &["m3u", "m3u8"]),
+  (FileKind::Lyrics, &["lrc"]),
+  (
+    FileKind::Artwork,
+    &["jpg", "jpeg", "png", "webp", "gif", "bmp"],
+  ),
+];
+
+lazy_static! {
+  /// Lowercase extensions without the leading dot. Each extension belongs to
+  /// one kind at most.
+  static ref EXTENSIONS: RwLock<HashMap<String, FileKind>> = RwLock::new(
+    DEFAULT_EXTENSIONS
+      .iter()
+      .flat_map(|(kind, extensions)| extensions.iter().map(|e| (e.to_string(), *kind)))
+      .collect()
+  );
+}
+
+fn normalize_extension(extension: &str) -> Result<String, ScanError> {
+  let extension = extension.trim().trim_start_matches('.').to_lowercase();
+  if extension.is_empty() || extension.contains(['.', '/', '\\']) {
+    return Err(ScanError::String(format!(
+      "Invalid extension {:?}",
+      extension
+    )));
+  }
+
+  Ok(extension)
+}
+
+/// Looks up the kind of `path` by its extension, ignoring case
+pub fn file_kind(path: &Path) -> Option<FileKind> {
+  let extension = path.extension()?.to_str()?.to_lowercase();
+  EXTENSIONS.read().unwrap().get(&extension).copied()
+}
+
+fn extensions_of(kind: FileKind) -> Vec<String> {
+  let mut extensions: Vec<String> = EXTENSIONS
+    .read()
+    .unwrap()
+    .iter()
+    .filter(|(_, k)| **k == kind)
+    .map(|(e, _)| e.clone())
+    .collect();
+  extensions.sort();
+  extensions
+}
+
+/// Adds extensions to one of the sets used to recognise files. An extension
+/// already registered under another kind is moved over.
+#[napi(
+  ts_args_type = "kind: 'audio' | 'playlist' | 'lyrics' | 'artwork', extensions: Array<string>"
+)]
+pub fn register_extensions(kind: String, extensions: Vec<String>) -> Result<(), napi::Error> {
+  let kind = FileKind::from_str(&kind)?;
+  let extensions = extensions
+    .iter()
+    .map(|e| normalize_extension(e))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  let mut registry = EXTENSIONS.write().unwrap();
+  for extension in extensions {
+    registry.insert(extension, kind);
   }
+  Ok(())
+}
 
-  if dir.is_file() {
-    if let Ok(metadata) = fs::metadata(&dir) {
-      let extension = dir
-        .extension()
+/// Removes extensions from whichever set they were registered in
+#[napi]
+pub fn unregister_extensions(extensions: Vec<String>) -> Result<(), napi::Error> {
+  let extensions = extensions
+    .iter()
+    .map(|e| normalize_extension(e))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  let mut registry = EXTENSIONS.write().unwrap();
+  for extension in extensions {
+    registry.remove(&extension);
+  }
+  Ok(())
+}
+
+#[napi(ts_args_type = "kind: 'audio' | 'playlist' | 'lyrics' | 'artwork'")]
+pub fn get_extensions(kind: String) -> Result<Vec<String>, napi::Error> {
+  Ok(extensions_of(FileKind::from_str(&kind)?))
+}
+
+pub fn mtime_millis(metadata: &fs::Metadata) -> Option<u64> {
+  let mtime = metadata.modified().ok()?;
+  Some(mtime.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+/// Lowercases and collapses whitespace, so that spelling variants of the
+/// same name share an id
+pub fn normalize_key(key: &str) -> String {
+  key
+    .split_whitespace()
+    .collect::<Vec<_>>()
+    .join(" ")
+    .to_lowercase()
+}
+
+/// UUID derived from `kind` and `key`, which stays the same across scans
+pub fn stable_id(kind: &str, key: &str) -> String {
+  let hash = blake3::Hasher::new()
+    .update(kind.as_bytes())
+    .update(&[0])
+    .update(key.as_bytes())
+    .finalize();
+  let mut bytes = [0; 16];
+  bytes.copy_from_slice(&hash.as_bytes()[..16]);
+
+  uuid::Builder::from_custom_bytes(bytes)
+    .into_uuid()
+    .to_string()
+}
+
+/// Ids albums by name and whoever they belong to, see `album_owner`
+pub fn album_id(owner: &str, album_name: &str) -> String {
+  stable_id(
+    "album",
+    &format!("{}\0{}", normalize_key(owner), normalize_key(album_name)),
+  )
+}
+
+/// What tells albums of the same name apart: the album artist, else the
+/// first track artist, else the folder. Untagged albums would all collapse
+/// into one otherwise.
+fn album_owner(album_artist: Option<&str>, artists: &[Artists], path: &Path) -> String {
+  album_artist
+    .or(artists.first().map(|artist| artist.artist_name.as_str()))
+    .map(str::to_string)
+    .unwrap_or_else(|| {
+      path
+        .parent()
+        .map(|dir| dir.to_string_lossy().to_string())
         .unwrap_or_default()
-        .to_str()
-        .unwrap_or_default();
-        if !extension.is_empty() {
-        if SONG_RE.is_match(extension) {
-          file_list.push((dir.clone(), metadata.len()));
+    })
+}
+
+pub fn artist_id(artist_name: &str) -> String {
+  stable_id("artist", &normalize_key(artist_name))
+}
+
+fn read_at<R: Read + Seek>(file: &mut R, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+  file.seek(SeekFrom::Start(offset))?;
+  match file.read_exact(buf) {
+    Ok(()) => Ok(true),
+    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+    Err(e) => Err(e),
+  }
+}
+
+/// Byte range of `file` left once leading ID3v2 tags, FLAC metadata blocks
+/// and trailing ID3v1 and APE tags are cut off
+fn audio_range<R: Read + Seek>(file: &mut R) -> io::Result<(u64, u64)> {
+  let mut start = 0;
+  let mut end = file.seek(SeekFrom::End(0))?;
+
+  let mut header = [0u8; 10];
+  while start + 10 <= end && read_at(file, start, &mut header)? && &header[..3] == b"ID3" {
+    let size = header[6..10]
+      .iter()
+      .fold(0u64, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    start += 10 + size + footer;
+  }
+
+  if read_at(file, start, &mut header[..4])? && &header[..4] == b"fLaC" {
+    start += 4;
+    loop {
+      if !read_at(file, start, &mut header[..4])? {
+        break;
+      }
+      let size = u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+      start += 4 + size;
+      if header[0] & 0x80 != 0 {
+        break;
+      }
+    }
+  }
+
+  let mut footer = [0u8; 32];
+  loop {
+    if end >= start + 128 && read_at(file, end - 128, &mut footer[..3])? && &footer[..3] == b"TAG" {
+      end -= 128;
+    } else if end >= start + 32
+      && read_at(file, end - 32, &mut footer)?
+      && &footer[..8] == b"APETAGEX"
+    {
+      let size = u64::from(u32::from_le_bytes([
+        footer[12], footer[13], footer[14], footer[15],
+      ]));
+      let header = if footer[23] & 0x80 != 0 { 32 } else { 0 };
+      // The size counts the footer itself, anything less is a broken tag
+      let cut = end.saturating_sub(size + header).max(start);
+      if size < 32 || cut >= end {
+        break;
+      }
+      end = cut;
+    } else {
+      break;
+    }
+  }
+
+  Ok((start.min(end), end))
+}
+
+/// Hex encoded blake3 hash of the audio in a file. Tags at either end and
+/// FLAC metadata are left out, so retagging a file keeps its hash. Tags kept
+/// inside the container, as in MP4 and Ogg, are still part of it.
+pub fn hash_file(path: &Path) -> Result<String, ScanError> {
+  let mut file = File::open(path)?;
+  let (start, end) = audio_range(&mut file)?;
+  file.seek(SeekFrom::Start(start))?;
+
+  let mut hasher = blake3::Hasher::new();
+  io::copy(&mut file.take(end - start), &mut hasher)?;
+  Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Short lowercase name for a container detected by lofty
+fn container_name(file_type: FileType) -> String {
+  match file_type {
+    FileType::Aac => "aac".to_string(),
+    FileType::Aiff => "aiff".to_string(),
+    FileType::Ape => "ape".to_string(),
+    FileType::Flac => "flac".to_string(),
+    FileType::Mpeg => "mpeg".to_string(),
+    FileType::Mp4 => "mp4".to_string(),
+    FileType::Mpc => "mpc".to_string(),
+    FileType::Opus => "opus".to_string(),
+    FileType::Vorbis => "vorbis".to_string(),
+    FileType::Speex => "speex".to_string(),
+    FileType::Wav => "wav".to_string(),
+    FileType::WavPack => "wavpack".to_string(),
+    FileType::Custom(name) => name.to_lowercase(),
+    other => format!("{:?}", other).to_lowercase(),
+  }
+}
+
+/// Looks for a known container in the first bytes of the file. The
+/// extension is deliberately not consulted.
+fn sniff_container(path: &Path) -> Option<FileType> {
+  let file = fs::File::open(path).ok()?;
+  Probe::new(BufReader::new(file))
+    .guess_file_type()
+    .ok()?
+    .file_type()
+}
+
+fn classify_file(
+  path: PathBuf,
+  metadata: &fs::Metadata,
+  sniff_content: bool,
+) -> Option<DiscoveredFile> {
+  let kind = match file_kind(&path) {
+    Some(kind) => kind,
+    None if sniff_content && sniff_container(&path).is_some() => FileKind::Audio,
+    None => return None,
+  };
+
+  match kind {
+    FileKind::Audio => match dunce::canonicalize(&path) {
+      Ok(canonical) => Some(DiscoveredFile::Song(FileStat {
+        path: canonical,
+        size: metadata.len(),
+        mtime: mtime_millis(metadata),
+      })),
+      Err(e) => Some(warning(&path, e)),
+    },
+    FileKind::Playlist => match dunce::canonicalize(&path) {
+      Ok(canonical) => Some(DiscoveredFile::Playlist(canonical)),
+      Err(e) => Some(warning(&path, e)),
+    },
+    FileKind::Lyrics | FileKind::Artwork => None,
+  }
+}
+
+/// Walks every root in parallel and streams songs and playlists to `tx` as
+/// they are found. Unreadable paths are reported as warnings and skipped.
+///
+/// Folders containing a `.nomedia` file are left out, and `.moosyncignore`
+/// files exclude paths below them using gitignore syntax. Both are reported
+/// as skipped paths.
+///
+/// Folders are tracked by device and inode, so symlink loops and folders
+/// reachable from several roots are only walked once. Files reached through
+/// more than one path are likewise only reported once.
+///
+/// With `sniff_content`, files without a registered extension are probed for
+/// a readable audio container and treated as songs if one is found.
+pub fn get_files_recursively(
+  dirs: &[PathBuf],
+  exclude: Arc<PathFilter>,
+  traversal: &TraversalPolicy,
+  sniff_content: bool,
+  control: &ScanControl,
+  threads: usize,
+  tx: Sender<DiscoveredFile>,
+) {
+  let visited_dirs: Arc<Mutex<HashSet<FileId>>> = Default::default();
+  let mut seen_files: HashSet<FileId> = HashSet::new();
+
+  for root in dirs {
+    // The root itself also passes through `process_read_dir`, so roots
+    // nested in an earlier root are skipped there
+    let root_device = match fs::metadata(root) {
+      Ok(metadata) => device_id(root, &metadata),
+      Err(_) => continue,
+    };
+
+    let walk_root = root.clone();
+    let walk_exclude = exclude.clone();
+    let walk_control = control.clone();
+    let walk_visited = visited_dirs.clone();
+    let walk_tx = tx.clone();
+    let same_filesystem = traversal.same_filesystem;
+
+    let mut walker = WalkDirGeneric::<(IgnoreStack, ())>::new(root)
+      .skip_hidden(false)
+      .follow_links(traversal.follow_symlinks)
+      .parallelism(Parallelism::RayonNewPool(threads))
+      .process_read_dir(move |depth, dir, ignore_stack, children| {
+        walk_control.wait_if_paused();
+        if walk_control.is_cancelled() {
+          children.clear();
+          return;
         }
 
-        if PLAYLIST_RE.is_match(extension) {
-          playlist_list.push(dir);
+        // `depth` is only missing for the call which yields the root itself
+        if depth.is_some() {
+          if let Some(marker) = find_child(children, NOMEDIA_FILE) {
+            children.clear();
+            walk_tx.send(skipped(dir, "nomedia", Some(&marker))).ok();
+            return;
+          }
+
+          if let Some(ignore_path) = find_child(children, IGNORE_FILE) {
+            match fs::read_to_string(&ignore_path)
+              .map_err(ScanError::from)
+              .and_then(|contents| IgnoreFile::parse(ignore_path.clone(), &contents))
+            {
+              Ok(ignore_file) => ignore_stack.push(ignore_file),
+              Err(e) => {
+                walk_tx.send(warning(&ignore_path, e)).ok();
+              }
+            }
+          }
         }
-      }
-      return Ok(FileList {
-        file_list,
-        playlist_list,
+
+        children.retain(|child| {
+          let Ok(entry) = child else {
+            return true;
+          };
+
+          let path = entry.path();
+          let is_dir = entry.file_type.is_dir();
+          if walk_exclude.is_excluded(&walk_root, &path, is_dir) {
+            walk_tx.send(skipped(&path, "exclude", None)).ok();
+            return false;
+          }
+
+          if let Some(ignore_file) = ignore_stack.ignored_by(&path, is_dir) {
+            walk_tx
+              .send(skipped(&path, "ignoreFile", Some(&ignore_file.path)))
+              .ok();
+            return false;
+          }
+
+          if !is_dir {
+            return true;
+          }
+
+          let Ok(metadata) = fs::metadata(&path) else {
+            return true;
+          };
+
+          if same_filesystem && device_id(&path, &metadata) != root_device {
+            return false;
+          }
+
+          walk_visited
+            .lock()
+            .unwrap()
+            .insert(file_id(&path, &metadata))
+        });
       });
+
+    if let Some(max_depth) = traversal.max_depth {
+      walker = walker.max_depth(max_depth);
     }
-  }
 
-  let dir_entries = fs::read_dir(dir)?;
+    for entry in walker {
+      if control.is_cancelled() {
+        return;
+      }
 
-  for entry in dir_entries {
-    if let Ok(entry) = entry {
-      let path = entry.path();
+      let discovered = match entry {
+        Ok(entry) => {
+          if let Some(e) = entry.read_children_error {
+            Some(warning(&entry.parent_path.join(&entry.file_name), e))
+          } else if entry.file_type.is_file() {
+            let path = entry.path();
+            match fs::metadata(&path) {
+              Ok(metadata) => {
+                if seen_files.insert(file_id(&path, &metadata)) {
+                  classify_file(path, &metadata, sniff_content)
+                } else {
+                  None
+                }
+              }
+              Err(e) => Some(warning(&path, e)),
+            }
+          } else {
+            None
+          }
+        }
+        Err(e) => Some(warning(e.path().unwrap_or(root), &e)),
+      };
 
-      let res = get_files_recursively(path)?;
-      file_list.extend_from_slice(&res.file_list);
-      playlist_list.extend_from_slice(&res.playlist_list);
+      if let Some(discovered) = discovered {
+        if tx.send(discovered).is_err() {
+          return;
+        }
+      }
     }
   }
-
-  Ok(FileList {
-    file_list,
-    playlist_list,
-  })
 }
 
 fn generate_image(data: &[u8], path: PathBuf, dimensions: u32) -> Result<(), ScanError> {
-  let img = image::load_from_memory(&data)?;
+  let img = image::load_from_memory(data)?;
 
   let width = NonZeroU32::new(img.width()).unwrap();
   let height = NonZeroU32::new(img.height()).unwrap();
@@ -119,23 +656,20 @@ fn generate_image(data: &[u8], path: PathBuf, dimensions: u32) -> Result<(), Sca
   Ok(())
 }
 
-fn store_picture(
-  thumbnail_dir: &PathBuf,
-  picture: &Picture,
-) -> Result<(PathBuf, PathBuf), ScanError> {
+fn store_picture(thumbnail_dir: &Path, picture: &Picture) -> Result<(PathBuf, PathBuf), ScanError> {
   let data = picture.data();
-  let hash = blake3::hash(&data).to_hex();
+  let hash = blake3::hash(data).to_hex();
   let hash_str = hash.as_str();
 
   let low_path = thumbnail_dir.join(format!("{}-low.png", hash_str));
   let high_path = thumbnail_dir.join(format!("{}.png", hash_str));
 
   if !Path::new(high_path.to_str().unwrap()).exists() {
-    generate_image(&data, high_path.clone(), 400)?;
+    generate_image(data, high_path.clone(), 400)?;
   }
 
   if !Path::new(low_path.to_str().unwrap()).exists() {
-    generate_image(&data, low_path.clone(), 80)?;
+    generate_image(data, low_path.clone(), 80)?;
   }
 
   Ok((
@@ -144,9 +678,30 @@ fn store_picture(
   ))
 }
 
-fn scan_lrc(mut path: PathBuf) -> Option<String> {
-  path.set_extension("lrc");
-  if path.exists() {
+/// Lyrics file next to `path` with the same name. Extensions match in any
+/// case, the usual lowercase spelling is checked first.
+fn lyrics_file(path: &Path) -> Option<PathBuf> {
+  let exact = extensions_of(FileKind::Lyrics)
+    .into_iter()
+    .map(|e| path.with_extension(e))
+    .find(|path| path.is_file());
+  if exact.is_some() {
+    return exact;
+  }
+
+  let stem = path.file_stem()?;
+  path
+    .parent()?
+    .read_dir()
+    .ok()?
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .find(|candidate| {
+      candidate.file_stem() == Some(stem) && file_kind(candidate) == Some(FileKind::Lyrics)
+    })
+}
+
+fn scan_lrc(path: &Path) -> Option<String> {
+  if let Some(path) = lyrics_file(path) {
     lazy_static! {
       static ref LRC_REGEX: Regex = Regex::new(r"\[\d{2}:\d{2}.\d{2}\]").unwrap();
     }
@@ -158,7 +713,7 @@ fn scan_lrc(mut path: PathBuf) -> Option<String> {
 
     let mut parsed_lyrics = "".to_string();
     let parsed = String::from_utf8_lossy(&data.unwrap()).to_string();
-    for line in parsed.split("\n") {
+    for line in parsed.split('\n') {
       if LRC_REGEX.is_match(line) {
         parsed_lyrics.push_str(&LRC_REGEX.replace_all(line, ""));
         parsed_lyrics.push('\n');
@@ -171,57 +726,311 @@ fn scan_lrc(mut path: PathBuf) -> Option<String> {
   None
 }
 
+/// Leading number of a tag such as `3` or `3/12`
+pub fn leading_number(value: &str) -> Option<u32> {
+  let digits: String = value
+    .trim()
+    .chars()
+    .take_while(|c| c.is_ascii_digit())
+    .collect();
+  digits.parse().ok()
+}
+
+/// First non-empty value stored under `key`
+fn tag_string(tag: &Tag, key: &ItemKey) -> Option<String> {
+  tag
+    .get_strings(key)
+    .map(str::trim)
+    .find(|value| !value.is_empty())
+    .map(str::to_string)
+}
+
+/// Value of a total tag, falling back to the part after the slash of values
+/// such as `3/12` stored under `number`. Zero means unknown.
+fn tag_total(tag: &Tag, total: &ItemKey, number: &ItemKey) -> Option<u32> {
+  tag_string(tag, total)
+    .and_then(|total| leading_number(&total))
+    .filter(|total| *total > 0)
+    .or_else(|| {
+      tag_string(tag, number).and_then(|number| {
+        number
+          .split_once('/')
+          .and_then(|(_, total)| leading_number(total))
+      })
+    })
+    .filter(|total| *total > 0)
+}
+
+// Names identifiers are stored under when lofty has no `ItemKey` for them,
+// as compared by `tag_name_key`
+const MB_RECORDING_ID: &[&str] = &["musicbrainztrackid", "musicbrainzrecordingid"];
+const MB_TRACK_ID: &[&str] = &["musicbrainzreleasetrackid"];
+const MB_WORK_ID: &[&str] = &["musicbrainzworkid"];
+const MB_ARTIST_ID: &[&str] = &["musicbrainzartistid"];
+const MB_RELEASE_ID: &[&str] = &["musicbrainzalbumid", "musicbrainzreleaseid"];
+const MB_RELEASE_GROUP_ID: &[&str] = &["musicbrainzreleasegroupid"];
+const MB_ALBUM_ARTIST_ID: &[&str] = &["musicbrainzalbumartistid"];
+const ACOUSTID_ID: &[&str] = &["acoustidid"];
+const DISCOGS_RELEASE_ID: &[&str] = &["discogsreleaseid", "discogsalbumid"];
+const DISCOGS_MASTER_ID: &[&str] = &["discogsmasterid", "discogsmasterreleaseid"];
+const DISCOGS_ARTIST_ID: &[&str] = &["discogsartistid"];
+
+/// Makes tag names comparable across formats. `ACOUSTID_ID`, `Acoustid Id`
+/// and the MP4 atom `----:com.apple.iTunes:Acoustid Id` all become
+/// `acoustidid`
+fn tag_name_key(name: &str) -> String {
+  let name = name.rsplit_once(':').map_or(name, |(_, name)| name);
+  name
+    .chars()
+    .filter(|c| c.is_alphanumeric())
+    .flat_map(char::to_lowercase)
+    .collect()
+}
+
+/// Text stored under `key` or, in any spelling, one of `names`
+fn tag_values<'a>(
+  tag: &'a Tag,
+  key: Option<&'a ItemKey>,
+  names: &'a [&str],
+) -> impl Iterator<Item = &'a str> {
+  tag
+    .items()
+    .filter(move |item| match item.key() {
+      ItemKey::Unknown(name) => names.contains(&tag_name_key(name).as_str()),
+      other => Some(other) == key,
+    })
+    .filter_map(|item| item.value().text())
+}
+
+/// Identifiers stored under `key` or one of `names`. Taggers put several ids
+/// into one value with a variety of separators, none of which appear in ids.
+fn tag_ids(tag: &Tag, key: Option<&ItemKey>, names: &[&str]) -> Vec<String> {
+  let mut seen = HashSet::new();
+  tag_values(tag, key, names)
+    .flat_map(|value| {
+      value.split(|c: char| matches!(c, '\0' | ';' | '/' | ',') || c.is_whitespace())
+    })
+    .filter(|id| !id.is_empty() && seen.insert(id.to_string()))
+    .map(str::to_string)
+    .collect()
+}
+
+fn tag_id(tag: &Tag, key: Option<&ItemKey>, names: &[&str]) -> Option<String> {
+  tag_ids(tag, key, names).into_iter().next()
+}
+
+/// Number in a gain or peak value such as `-6.54 dB`, also with a decimal
+/// comma
+fn gain_number(value: &str) -> Option<f64> {
+  let value = value.trim();
+  let value = value
+    .strip_suffix("dB")
+    .or_else(|| value.strip_suffix("db"))
+    .or_else(|| value.strip_suffix("DB"))
+    .unwrap_or(value);
+  value
+    .trim()
+    .trim_start_matches('+')
+    .replace(',', ".")
+    .parse::<f64>()
+    .ok()
+    .filter(|number| number.is_finite())
+}
+
+fn replay_gain_tags(tag: &Tag) -> Option<ReplayGain> {
+  let value = |key: ItemKey, name: &str| tag_values(tag, Some(&key), &[name]).find_map(gain_number);
+
+  let gain = ReplayGain {
+    track_gain: value(ItemKey::ReplayGainTrackGain, "replaygaintrackgain"),
+    track_peak: value(ItemKey::ReplayGainTrackPeak, "replaygaintrackpeak"),
+    album_gain: value(ItemKey::ReplayGainAlbumGain, "replaygainalbumgain"),
+    album_peak: value(ItemKey::ReplayGainAlbumPeak, "replaygainalbumpeak"),
+    source: "replaygain".to_string(),
+  };
+  (gain.track_gain.is_some() || gain.album_gain.is_some()).then_some(gain)
+}
+
+/// Opus gains are stored as Q7.8 fixed point numbers relative to -23 LUFS.
+/// They are moved to the -18 LUFS ReplayGain reference.
+fn r128_tags(tag: &Tag) -> Option<ReplayGain> {
+  let value = |name: &str| {
+    tag_values(tag, None, &[name])
+      .find_map(|value| value.trim().parse::<i16>().ok())
+      .map(|gain| gain as f64 / 256f64 + 5f64)
+  };
+
+  let gain = ReplayGain {
+    track_gain: value("r128trackgain"),
+    album_gain: value("r128albumgain"),
+    source: "r128".to_string(),
+    ..Default::default()
+  };
+  (gain.track_gain.is_some() || gain.album_gain.is_some()).then_some(gain)
+}
+
+/// ID3v2 RVA2 frames, which the generic tag leaves out. Frames identified as
+/// `album` hold the album gain, any other the track gain.
+fn rva2_frames(tag: &Id3v2Tag) -> Option<ReplayGain> {
+  let mut gain = ReplayGain {
+    source: "rva2".to_string(),
+    ..Default::default()
+  };
+  for frame in tag {
+    let FrameValue::RelativeVolumeAdjustment(rva) = frame.content() else {
+      continue;
+    };
+    let Some(channel) = rva.channels.get(&ChannelType::MasterVolume) else {
+      continue;
+    };
+
+    let adjustment = channel.volume_adjustment as f64 / 512f64;
+    // The peak is an unsigned integer of the given width, full scale being
+    // its top bit
+    let bits = channel.bits_representing_peak as i32;
+    let peak = channel
+      .peak_volume
+      .as_ref()
+      .filter(|bytes| bits > 0 && bytes.len() <= 8)
+      .map(|bytes| {
+        let raw = bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        raw as f64 / 2f64.powi(bits - 1)
+      });
+
+    if rva.identification.to_lowercase().contains("album") {
+      gain.album_gain = Some(adjustment);
+      gain.album_peak = peak;
+    } else if gain.track_gain.is_none() {
+      gain.track_gain = Some(adjustment);
+      gain.track_peak = peak;
+    }
+  }
+
+  (gain.track_gain.is_some() || gain.album_gain.is_some()).then_some(gain)
+}
+
+/// Values lofty leaves out when turning a file's own tags into a `Tag`
+#[derive(Debug, Default)]
+struct NativeTags {
+  /// MP4 keeps the tempo as an integer `tmpo` atom
+  bpm: Option<f64>,
+  /// ID3v2 `RVA2` frames have no generic item
+  replay_gain: Option<ReplayGain>,
+}
+
+fn ilst_bpm(ilst: &Ilst) -> Option<f64> {
+  ilst
+    .get(&AtomIdent::Fourcc(*b"tmpo"))?
+    .data()
+    .find_map(|data| match data {
+      AtomData::SignedInteger(bpm) => Some(*bpm as f64),
+      AtomData::UnsignedInteger(bpm) => Some(*bpm as f64),
+      AtomData::UTF8(bpm) => bpm.trim().parse::<f64>().ok(),
+      _ => None,
+    })
+}
+
+/// Reads `path` once, by its extension when `by_extension` is set or by its
+/// content otherwise, along with whatever `NativeTags` it has
+fn read_tagged(path: &Path, by_extension: bool) -> Result<(TaggedFile, NativeTags), LoftyError> {
+  let probe = Probe::open(path)?;
+  let probe = if by_extension {
+    probe
+  } else {
+    probe.guess_file_type()?
+  };
+
+  let options = ParseOptions::new();
+  match probe.file_type() {
+    Some(FileType::Mp4) => {
+      let file = Mp4File::read_from(&mut probe.into_inner(), options)?;
+      let native = NativeTags {
+        bpm: file.ilst().and_then(ilst_bpm),
+        ..Default::default()
+      };
+      Ok((file.into(), native))
+    }
+    Some(FileType::Mpeg) => {
+      let file = MpegFile::read_from(&mut probe.into_inner(), options)?;
+      let native = NativeTags {
+        replay_gain: file.id3v2().and_then(rva2_frames),
+        ..Default::default()
+      };
+      Ok((file.into(), native))
+    }
+    Some(FileType::Wav) => {
+      let file = WavFile::read_from(&mut probe.into_inner(), options)?;
+      let native = NativeTags {
+        replay_gain: file.id3v2().and_then(rva2_frames),
+        ..Default::default()
+      };
+      Ok((file.into(), native))
+    }
+    Some(FileType::Aiff) => {
+      let file = AiffFile::read_from(&mut probe.into_inner(), options)?;
+      let native = NativeTags {
+        replay_gain: file.id3v2().and_then(rva2_frames),
+        ..Default::default()
+      };
+      Ok((file.into(), native))
+    }
+    _ => Ok((probe.read()?, NativeTags::default())),
+  }
+}
+
 pub fn scan_file(
   path: &PathBuf,
-  thumbnail_dir: &PathBuf,
+  thumbnail_dir: &Path,
   playlist_id: &Option<String>,
   size: u64,
   guess: bool,
-  artist_split: &str,
+  credits: &CreditParser,
 ) -> Result<Song, ScanError> {
-  let file: lofty::TaggedFile;
-
-  let mut song: Song = Song::default();
-  song._id = Uuid::new_v4().to_string();
-  song.title = Some(path.file_name().unwrap().to_string_lossy().to_string());
-  song.path = Some(dunce::canonicalize(path)?.to_string_lossy().to_string());
-  song.size = Some(size as u32);
-  song.duration = Some(0f64);
-  song.song_type = "LOCAL".to_string();
-
-  if guess {
-    let file_res = read_from_path(path.clone());
+  let canonical = dunce::canonicalize(path)?.to_string_lossy().to_string();
+  let mut song = Song {
+    _id: stable_id("song", &canonical),
+    title: Some(path.file_name().unwrap().to_string_lossy().to_string()),
+    path: Some(canonical),
+    size: Some(size as u32),
+    mtime: fs::metadata(path)
+      .ok()
+      .and_then(|metadata| mtime_millis(&metadata))
+      .map(|mtime| mtime as f64),
+    duration: Some(0f64),
+    song_type: "LOCAL".to_string(),
+    ..Default::default()
+  };
+
+  let (file, native) = if guess {
+    let file_res = read_tagged(path, true);
     if file_res.is_err() {
       println!("Error reading file {:?}", file_res.err());
-      return scan_file(path, thumbnail_dir, playlist_id, size, false, artist_split);
+      return scan_file(path, thumbnail_dir, playlist_id, size, false, credits);
     }
 
-    file = file_res.unwrap()
+    file_res.unwrap()
   } else {
-    let file_res = Probe::open(path.clone())?.guess_file_type()?.read();
+    let file_res = read_tagged(path, false);
     if file_res.is_err() {
       println!("Error reading file without guess {:?}", file_res.err());
+
+      // Keep unreadable files visible as an entry named after the file
+      song.container = path.extension().map(|e| e.to_string_lossy().to_lowercase());
       return Ok(song);
     }
-    file = file_res.unwrap();
-  }
+    file_res.unwrap()
+  };
 
   let properties = file.properties();
-  let mut tags = file.primary_tag();
-  if tags.is_none() {
-    tags = file.first_tag();
-  }
+  let tags = file.primary_tag().or(file.first_tag());
   song.bitrate = Some(properties.audio_bitrate().unwrap_or_default() * 1000);
   song.sample_rate = properties.sample_rate();
   song.duration = Some(properties.duration().as_secs() as f64);
+  song.container = Some(container_name(file.file_type()));
   song.playlist_id = playlist_id.clone();
 
-  if tags.is_some() {
-    let metadata = tags.unwrap();
-
-    let picture = metadata.pictures().get(0);
-    if picture.is_some() {
-      if let Ok((high_path, low_path)) = store_picture(thumbnail_dir, picture.unwrap()) {
+  if let Some(metadata) = tags {
+    if let Some(picture) = metadata.pictures().first() {
+      if let Ok((high_path, low_path)) = store_picture(thumbnail_dir, picture) {
         song.high_path = Some(high_path.to_string_lossy().to_string());
         song.low_path = Some(low_path.to_string_lossy().to_string());
       }
@@ -239,7 +1048,9 @@ pub fn scan_file(
               .to_string_lossy()
               .to_lowercase();
 
-            if file_name.starts_with("cover") {
+            if file_name.starts_with("cover")
+              && file_kind(&dir_entry.path()) == Some(FileKind::Artwork)
+            {
               return Some(dir_entry.path().to_string_lossy().to_string());
             }
           }
@@ -253,7 +1064,7 @@ pub fn scan_file(
       .map(str::to_string);
 
     if lyrics.is_none() {
-      lyrics = scan_lrc(path.clone());
+      lyrics = scan_lrc(path);
     }
 
     song.title = metadata
@@ -261,40 +1072,584 @@ pub fn scan_file(
       .map(|s| s.to_string())
       .or(path.file_name().map(|s| s.to_string_lossy().to_string()));
     // song.album = metadata.album().map(|s| s.to_string());
-    let artists: Option<Vec<Artists>> = metadata.artist().map(|s| {
-      s.split(artist_split)
-        .map(|s| Artists {
-          artist_id: Uuid::new_v4().to_string(),
-          artist_name: s.trim().to_string(),
-        })
-        .collect()
-    });
+    // Every value stored under the key counts, including ID3v2.4 style null
+    // separated ones
+    let mut artists: Vec<Artists> = credits
+      .parse(
+        metadata.get_strings(&ItemKey::TrackArtist),
+        metadata.get_strings(&ItemKey::Remixer),
+        song.title.as_deref(),
+      )
+      .into_iter()
+      .map(|(name, role)| Artists {
+        artist_id: artist_id(&name),
+        artist_name: name,
+        role: credits.roles().then(|| role.to_string()),
+        external_ids: None,
+      })
+      .collect();
+
+    let artist_ids = tag_ids(metadata, Some(&ItemKey::MusicBrainzArtistId), MB_ARTIST_ID);
+    let discogs_artist_ids = tag_ids(metadata, None, DISCOGS_ARTIST_ID);
+    // Ids can only be matched to artists by position when every artist has one
+    let artists_len = artists.len();
+    for (i, artist) in artists.iter_mut().enumerate() {
+      let ids = ArtistIds {
+        musicbrainz_artist_id: (artist_ids.len() == artists_len).then(|| artist_ids[i].clone()),
+        discogs_artist_id: (discogs_artist_ids.len() == artists_len)
+          .then(|| discogs_artist_ids[i].clone()),
+      };
+      artist.external_ids = (ids != ArtistIds::default()).then_some(ids);
+    }
+
+    let song_ids = SongIds {
+      musicbrainz_recording_id: tag_id(
+        metadata,
+        Some(&ItemKey::MusicBrainzRecordingId),
+        MB_RECORDING_ID,
+      ),
+      musicbrainz_track_id: tag_id(metadata, Some(&ItemKey::MusicBrainzTrackId), MB_TRACK_ID),
+      musicbrainz_work_id: tag_id(metadata, Some(&ItemKey::MusicBrainzWorkId), MB_WORK_ID),
+      musicbrainz_artist_ids: artist_ids,
+      acoustid_id: tag_id(metadata, None, ACOUSTID_ID),
+    };
+    song.external_ids = (song_ids != SongIds::default()).then_some(song_ids);
 
-    let album = metadata.album();
-    if album.is_some() {
+    if let Some(album) = metadata.album() {
       song.track_no = metadata
         .get_string(&lofty::ItemKey::TrackNumber)
         .map(|s| s.to_owned());
+      song.disc_no = metadata
+        .get_string(&lofty::ItemKey::DiscNumber)
+        .map(|s| s.to_owned());
+
+      let album_artists: Vec<String> = credits
+        .parse(metadata.get_strings(&ItemKey::AlbumArtist), [], None)
+        .into_iter()
+        .filter(|(_, role)| *role == PRIMARY)
+        .map(|(name, _)| name)
+        .collect();
+      let album_artist = (!album_artists.is_empty()).then(|| credits.join(&album_artists));
+
+      let album_ids = AlbumIds {
+        musicbrainz_release_id: tag_id(
+          metadata,
+          Some(&ItemKey::MusicBrainzReleaseId),
+          MB_RELEASE_ID,
+        ),
+        musicbrainz_release_group_id: tag_id(
+          metadata,
+          Some(&ItemKey::MusicBrainzReleaseGroupId),
+          MB_RELEASE_GROUP_ID,
+        ),
+        musicbrainz_album_artist_ids: tag_ids(
+          metadata,
+          Some(&ItemKey::MusicBrainzReleaseArtistId),
+          MB_ALBUM_ARTIST_ID,
+        ),
+        discogs_release_id: tag_id(metadata, None, DISCOGS_RELEASE_ID),
+        discogs_master_id: tag_id(metadata, None, DISCOGS_MASTER_ID),
+      };
 
       song.album = Some(Album {
-        album_id: Uuid::new_v4().to_string(),
-        album_name: album.unwrap().to_string(),
+        album_id: album_id(
+          &album_owner(album_artist.as_deref(), &artists, path),
+          &album,
+        ),
+        album_name: album.to_string(),
         album_cover_path_high: song.high_path.clone(),
         album_cover_path_low: song.low_path.clone(),
-        album_artist: metadata
-          .get_string(&lofty::ItemKey::AlbumArtist)
-          .map(|s| s.to_owned()),
+        album_artist,
+        external_ids: (album_ids != AlbumIds::default()).then_some(album_ids),
       })
     }
 
-    if artists.is_some() {
-      song.artists = artists.unwrap();
+    if !artists.is_empty() {
+      song.artists = artists;
     }
 
     song.year = metadata.year().map(|s| s.to_string());
     song.genre = metadata.genre().map(|s| vec![s.to_string()]);
     song.lyrics = lyrics;
+
+    song.track_total = tag_total(metadata, &ItemKey::TrackTotal, &ItemKey::TrackNumber);
+    song.disc_total = tag_total(metadata, &ItemKey::DiscTotal, &ItemKey::DiscNumber);
+    song.composer = tag_string(metadata, &ItemKey::Composer);
+    song.conductor = tag_string(metadata, &ItemKey::Conductor);
+    song.lyricist = tag_string(metadata, &ItemKey::Lyricist);
+    song.bpm = tag_string(metadata, &ItemKey::Bpm)
+      .and_then(|bpm| bpm.parse::<f64>().ok())
+      .or(native.bpm)
+      .filter(|bpm| *bpm > 0f64);
+    song.initial_key = tag_string(metadata, &ItemKey::InitialKey);
+    song.comment = tag_string(metadata, &ItemKey::Comment);
+    // iTunes keeps grouping in GRP1 since TIT1 became the work
+    song.grouping = tag_string(metadata, &ItemKey::ContentGroup)
+      .or_else(|| tag_string(metadata, &ItemKey::AppleId3v2ContentGroup));
+    song.compilation = tag_string(metadata, &ItemKey::FlagCompilation).and_then(|flag| match flag
+      .to_lowercase()
+      .as_str()
+    {
+      "1" | "true" | "yes" => Some(true),
+      "0" | "false" | "no" => Some(false),
+      _ => None,
+    });
+    song.copyright = tag_string(metadata, &ItemKey::CopyrightMessage);
+    song.label =
+      tag_string(metadata, &ItemKey::Label).or_else(|| tag_string(metadata, &ItemKey::Publisher));
+    song.isrc = tag_string(metadata, &ItemKey::Isrc);
+    song.title_sort = tag_string(metadata, &ItemKey::TrackTitleSortOrder);
+    song.artist_sort = tag_string(metadata, &ItemKey::TrackArtistSortOrder);
+    song.album_sort = tag_string(metadata, &ItemKey::AlbumTitleSortOrder);
+
+    song.replay_gain = replay_gain_tags(metadata)
+      .or_else(|| r128_tags(metadata))
+      .or(native.replay_gain);
   }
 
   Ok(song)
 }
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+  use lofty::{
+    id3::v2::{
+      ChannelInformation, Frame, FrameFlags, RelativeVolumeAdjustmentFrame, TextInformationFrame,
+    },
+    mp4::Atom,
+    ogg::VorbisComments,
+    TagType, TextEncoding,
+  };
+
+  const AUDIO: &[u8] = b"\xff\xfb\x90\x00 not really mpeg frames";
+
+  fn id3v2(payload: &[u8]) -> Vec<u8> {
+    let size = payload.len() as u32;
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend([21, 14, 7, 0].map(|shift| ((size >> shift) & 0x7f) as u8));
+    tag.extend(payload);
+    tag
+  }
+
+  fn ape(items: &[u8], with_header: bool) -> Vec<u8> {
+    let footer = |flags: u32| {
+      let mut footer = b"APETAGEX".to_vec();
+      footer.extend(2000u32.to_le_bytes());
+      footer.extend((items.len() as u32 + 32).to_le_bytes());
+      footer.extend(1u32.to_le_bytes());
+      footer.extend(flags.to_le_bytes());
+      footer.extend([0; 8]);
+      footer
+    };
+
+    let mut tag = vec![];
+    if with_header {
+      tag.extend(footer(0xa000_0000));
+    }
+    tag.extend(items);
+    tag.extend(footer(if with_header { 0x8000_0000 } else { 0 }));
+    tag
+  }
+
+  fn range(bytes: &[u8]) -> (u64, u64) {
+    audio_range(&mut io::Cursor::new(bytes)).unwrap()
+  }
+
+  fn audio_at(bytes: &[u8]) -> &[u8] {
+    let (start, end) = range(bytes);
+    &bytes[start as usize..end as usize]
+  }
+
+  #[test]
+  fn skips_id3v2_tags() {
+    let file = [id3v2(b"TIT2 and padding\0\0\0"), id3v2(b""), AUDIO.to_vec()].concat();
+    assert_eq!(audio_at(&file), AUDIO);
+  }
+
+  #[test]
+  fn skips_flac_metadata_blocks() {
+    let mut file = b"fLaC".to_vec();
+    file.extend([0x00, 0, 0, 34]);
+    file.extend([0; 34]);
+    file.extend([0x84, 0, 0, 5]);
+    file.extend(b"vorbi");
+    file.extend(AUDIO);
+    assert_eq!(audio_at(&file), AUDIO);
+
+    let tagged = [id3v2(b"x"), file].concat();
+    assert_eq!(audio_at(&tagged), AUDIO);
+  }
+
+  #[test]
+  fn skips_trailing_id3v1_and_ape_tags() {
+    let mut id3v1 = b"TAG".to_vec();
+    id3v1.extend([b' '; 125]);
+
+    let file = [AUDIO, &ape(b"Title\0Song", true), &id3v1].concat();
+    assert_eq!(audio_at(&file), AUDIO);
+
+    let file = [AUDIO, &ape(b"Title\0Song", false)].concat();
+    assert_eq!(audio_at(&file), AUDIO);
+  }
+
+  #[test]
+  fn stops_at_broken_ape_footers() {
+    let mut footer = b"APETAGEX".to_vec();
+    footer.extend([0; 24]);
+    let file = [AUDIO, &footer].concat();
+    assert_eq!(range(&file), (0, file.len() as u64));
+
+    // A size running past the start of the file leaves nothing to cut
+    let mut footer = b"APETAGEX".to_vec();
+    footer.extend([0; 4]);
+    footer.extend(u32::MAX.to_le_bytes());
+    footer.extend([0; 16]);
+    assert_eq!(range(&footer), (0, 0));
+    assert_eq!(range(&[]), (0, 0));
+  }
+
+  #[test]
+  fn hashes_audio_regardless_of_tags() {
+    let dir = env::temp_dir().join(format!("moosync-hash-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, bytes: &[u8]| {
+      let path = dir.join(name);
+      fs::write(&path, bytes).unwrap();
+      path
+    };
+
+    let plain = write("plain.mp3", AUDIO);
+    let tagged = write(
+      "tagged.mp3",
+      &[&id3v2(b"TIT2")[..], AUDIO, &ape(b"x", true)].concat(),
+    );
+    let other = write("other.mp3", b"other audio");
+
+    let hash = hash_file(&plain).unwrap();
+    assert_eq!(hash_file(&tagged).unwrap(), hash);
+    assert_ne!(hash_file(&other).unwrap(), hash);
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn normalizes_keys() {
+    assert_eq!(normalize_key("  The   Beatles "), "the beatles");
+    assert_eq!(normalize_key("AC/DC\tLive"), "ac/dc live");
+    assert_eq!(normalize_key("Björk"), "björk");
+    assert_eq!(normalize_key(""), "");
+  }
+
+  #[test]
+  fn derives_stable_ids() {
+    let id = stable_id("artist", "the beatles");
+    assert_eq!(id, stable_id("artist", "the beatles"));
+    assert!(uuid::Uuid::parse_str(&id).is_ok());
+
+    assert_ne!(id, stable_id("album", "the beatles"));
+    assert_ne!(id, stable_id("artist", "the rolling stones"));
+    // The kind and key don't run into each other
+    assert_ne!(stable_id("ab", "c"), stable_id("a", "bc"));
+
+    assert_eq!(artist_id("  The  BEATLES"), artist_id("the beatles"));
+  }
+
+  #[test]
+  fn tells_untagged_albums_apart() {
+    let artists = [Artists {
+      artist_name: "Track Artist".to_string(),
+      ..Default::default()
+    }];
+    let path = Path::new("/music/Album/01.flac");
+
+    assert_eq!(
+      album_owner(Some("Album Artist"), &artists, path),
+      "Album Artist"
+    );
+    assert_eq!(album_owner(None, &artists, path), "Track Artist");
+    assert_eq!(album_owner(None, &[], path), "/music/Album");
+    assert_ne!(
+      album_id(&album_owner(None, &[], path), "Greatest Hits"),
+      album_id(
+        &album_owner(None, &[], Path::new("/music/Other/01.flac")),
+        "Greatest Hits"
+      )
+    );
+  }
+
+  #[test]
+  fn reads_leading_numbers() {
+    assert_eq!(leading_number("3/12"), Some(3));
+    assert_eq!(leading_number(" 07 "), Some(7));
+    assert_eq!(leading_number("2019-05-01"), Some(2019));
+    assert_eq!(leading_number("0"), Some(0));
+    assert_eq!(leading_number("A1"), None);
+    assert_eq!(leading_number(""), None);
+  }
+
+  #[test]
+  fn reads_totals() {
+    let total = |pairs: &[(ItemKey, &str)]| {
+      let mut tag = Tag::new(TagType::VorbisComments);
+      for (key, value) in pairs {
+        tag.insert_text(key.clone(), value.to_string());
+      }
+      tag_total(&tag, &ItemKey::TrackTotal, &ItemKey::TrackNumber)
+    };
+
+    assert_eq!(total(&[(ItemKey::TrackTotal, "12")]), Some(12));
+    assert_eq!(total(&[(ItemKey::TrackNumber, "3/12")]), Some(12));
+    assert_eq!(
+      total(&[(ItemKey::TrackTotal, "10"), (ItemKey::TrackNumber, "3/12")]),
+      Some(10)
+    );
+    assert_eq!(
+      total(&[(ItemKey::TrackTotal, "0"), (ItemKey::TrackNumber, "3/12")]),
+      Some(12)
+    );
+    assert_eq!(total(&[(ItemKey::TrackNumber, "3/0")]), None);
+    assert_eq!(total(&[(ItemKey::TrackNumber, "3")]), None);
+    assert_eq!(total(&[]), None);
+  }
+
+  #[test]
+  fn compares_tag_names_across_formats() {
+    for name in [
+      "ACOUSTID_ID",
+      "Acoustid Id",
+      "acoustid_id",
+      "----:com.apple.iTunes:Acoustid Id",
+    ] {
+      assert_eq!(tag_name_key(name), "acoustidid");
+    }
+    assert_eq!(tag_name_key("DISCOGS_RELEASE_ID"), "discogsreleaseid");
+  }
+
+  /// The same ids in each format's own spelling
+  fn tags_with_ids() -> [Tag; 3] {
+    let mut id3 = Id3v2Tag::new();
+    for (description, content) in [
+      ("MusicBrainz Album Id", "release"),
+      ("MusicBrainz Artist Id", "one/two"),
+      ("Acoustid Id", "acoustid"),
+      ("DISCOGS_RELEASE_ID", "discogs"),
+    ] {
+      id3.insert_user_text(description.to_string(), content.to_string());
+    }
+    id3.insert(
+      Frame::new(
+        "TSRC",
+        FrameValue::Text(TextInformationFrame {
+          encoding: TextEncoding::UTF8,
+          value: "USRC17607839".to_string(),
+        }),
+        FrameFlags::default(),
+      )
+      .unwrap(),
+    );
+
+    let mut vorbis = VorbisComments::default();
+    for (key, value) in [
+      ("MUSICBRAINZ_ALBUMID", "release"),
+      ("MUSICBRAINZ_ARTISTID", "one"),
+      ("MUSICBRAINZ_ARTISTID", "two"),
+      ("ACOUSTID_ID", "acoustid"),
+      ("DISCOGS_RELEASE_ID", "discogs"),
+      ("ISRC", "USRC17607839"),
+    ] {
+      vorbis.push(key.to_string(), value.to_string());
+    }
+
+    let mut ilst = Ilst::new();
+    for (name, value) in [
+      ("MusicBrainz Album Id", "release"),
+      ("MusicBrainz Artist Id", "one; two"),
+      ("Acoustid Id", "acoustid"),
+      ("DISCOGS_RELEASE_ID", "discogs"),
+      ("ISRC", "USRC17607839"),
+    ] {
+      ilst.insert(Atom::new(
+        AtomIdent::Freeform {
+          mean: "com.apple.iTunes".into(),
+          name: name.into(),
+        },
+        AtomData::UTF8(value.to_string()),
+      ));
+    }
+
+    [id3.into(), vorbis.into(), ilst.into()]
+  }
+
+  #[test]
+  fn reads_ids_in_every_spelling() {
+    for tag in tags_with_ids() {
+      let format = tag.tag_type();
+      assert_eq!(
+        tag_id(&tag, Some(&ItemKey::MusicBrainzReleaseId), MB_RELEASE_ID).as_deref(),
+        Some("release"),
+        "{:?}",
+        format
+      );
+      assert_eq!(
+        tag_ids(&tag, Some(&ItemKey::MusicBrainzArtistId), MB_ARTIST_ID),
+        ["one", "two"],
+        "{:?}",
+        format
+      );
+      assert_eq!(
+        tag_id(&tag, None, ACOUSTID_ID).as_deref(),
+        Some("acoustid"),
+        "{:?}",
+        format
+      );
+      assert_eq!(
+        tag_id(&tag, None, DISCOGS_RELEASE_ID).as_deref(),
+        Some("discogs"),
+        "{:?}",
+        format
+      );
+      assert_eq!(
+        tag_string(&tag, &ItemKey::Isrc).as_deref(),
+        Some("USRC17607839"),
+        "{:?}",
+        format
+      );
+    }
+  }
+
+  #[test]
+  fn moves_r128_gains_to_the_replaygain_reference() {
+    let mut vorbis = VorbisComments::default();
+    vorbis.push("R128_TRACK_GAIN".to_string(), "-2560".to_string());
+    vorbis.push("R128_ALBUM_GAIN".to_string(), "384".to_string());
+    let gain = r128_tags(&vorbis.into()).unwrap();
+    assert_eq!(gain.track_gain, Some(-5.0));
+    assert_eq!(gain.album_gain, Some(6.5));
+    assert_eq!((gain.track_peak, gain.album_peak), (None, None));
+    assert_eq!(gain.source, "r128");
+
+    let mut vorbis = VorbisComments::default();
+    vorbis.push("R128_TRACK_GAIN".to_string(), "-6 dB".to_string());
+    assert!(r128_tags(&vorbis.into()).is_none());
+  }
+
+  fn rva2(
+    identification: &str,
+    channel_type: ChannelType,
+    adjustment: i16,
+    peak: &[u8],
+  ) -> Frame<'static> {
+    let channel = ChannelInformation {
+      channel_type,
+      volume_adjustment: adjustment,
+      bits_representing_peak: (peak.len() * 8) as u8,
+      peak_volume: Some(peak.to_vec()),
+    };
+    Frame::new(
+      "RVA2",
+      FrameValue::RelativeVolumeAdjustment(RelativeVolumeAdjustmentFrame {
+        identification: identification.to_string(),
+        channels: HashMap::from([(channel_type, channel)]),
+      }),
+      FrameFlags::default(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn reads_rva2_frames() {
+    let mut tag = Id3v2Tag::new();
+    tag.insert(rva2(
+      "track",
+      ChannelType::MasterVolume,
+      -3328,
+      &[0x40, 0x00],
+    ));
+    tag.insert(rva2(
+      "ALBUM",
+      ChannelType::MasterVolume,
+      1024,
+      &[0x80, 0x00],
+    ));
+    let gain = rva2_frames(&tag).unwrap();
+    assert_eq!((gain.track_gain, gain.track_peak), (Some(-6.5), Some(0.5)));
+    assert_eq!((gain.album_gain, gain.album_peak), (Some(2.0), Some(1.0)));
+    assert_eq!(gain.source, "rva2");
+
+    // Only the master volume counts
+    let mut tag = Id3v2Tag::new();
+    tag.insert(rva2("track", ChannelType::FrontLeft, -3328, &[0x40]));
+    assert!(rva2_frames(&tag).is_none());
+  }
+
+  #[test]
+  fn registers_extensions_in_any_case() {
+    register_extensions(
+      "audio".to_string(),
+      vec![".XM".to_string(), "Mod".to_string()],
+    )
+    .unwrap();
+    let audio = get_extensions("audio".to_string()).unwrap();
+    assert!(audio.contains(&"xm".to_string()) && audio.contains(&"mod".to_string()));
+    assert_eq!(
+      file_kind(Path::new("/music/song.xm")),
+      Some(FileKind::Audio)
+    );
+    assert_eq!(
+      file_kind(Path::new("/music/SONG.MOD")),
+      Some(FileKind::Audio)
+    );
+
+    unregister_extensions(vec!["xm".to_string(), ".MOD".to_string()]).unwrap();
+    assert_eq!(file_kind(Path::new("/music/song.xm")), None);
+
+    assert!(register_extensions("audio".to_string(), vec!["tar.gz".to_string()]).is_err());
+    assert!(register_extensions("audio".to_string(), vec![".".to_string()]).is_err());
+    assert!(get_extensions("video".to_string()).is_err());
+  }
+
+  #[test]
+  fn matches_whole_extensions_only() {
+    assert_eq!(
+      file_kind(Path::new("/music/song.MP3")),
+      Some(FileKind::Audio)
+    );
+    assert_eq!(
+      file_kind(Path::new("/music/list.M3U8")),
+      Some(FileKind::Playlist)
+    );
+    assert_eq!(file_kind(Path::new("/music/song.mp3bak")), None);
+    assert_eq!(file_kind(Path::new("/music/list.m3u8.tmp")), None);
+    assert_eq!(file_kind(Path::new("/music/mp3")), None);
+  }
+
+  #[test]
+  fn finds_lyrics_in_any_case() {
+    let dir = env::temp_dir().join("moosync-lyrics-test");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("song.Lrc"), "[ar:Artist]\n[00:01.00]Hello\n").unwrap();
+    fs::write(dir.join("song.lrc.bak"), "[00:01.00]Old\n").unwrap();
+
+    assert_eq!(scan_lrc(&dir.join("song.mp3")).as_deref(), Some("Hello\n"));
+    assert_eq!(scan_lrc(&dir.join("other.mp3")), None);
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn reads_gain_numbers() {
+    assert_eq!(gain_number("-6.54 dB"), Some(-6.54));
+    assert_eq!(gain_number("+1.50 dB"), Some(1.5));
+    assert_eq!(gain_number("-3,25 db"), Some(-3.25));
+    assert_eq!(gain_number(" 0.988000 "), Some(0.988));
+    assert_eq!(gain_number("2DB"), Some(2.0));
+    assert_eq!(gain_number("loud"), None);
+    assert_eq!(gain_number("NaN dB"), None);
+    assert_eq!(gain_number("inf"), None);
+    assert_eq!(gain_number(""), None);
+  }
+}